@@ -1,3 +1,3 @@
 #!/bin/bash
 
-cargo run -- --path=./examples/vscode/conf > ./examples/vscode/conf/output.json
\ No newline at end of file
+cargo run -- --path=./examples/vscode/conf -o ./examples/vscode/conf/output.json
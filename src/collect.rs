@@ -3,20 +3,126 @@
 use crate::component::rule::{self, Edge, Key, Rules};
 use serde_json as json;
 
+/// Table of the root config configuring **Thomson** itself, it is never compiled
+pub const CONFIG_KEY: &str = "thomson";
+
 /// Collect `JSON` format rules.  
 /// returns a trie tree as rule set.
 pub fn collect_rules(json_value: json::Value) -> Rules {
     let mut rules = Rules::new();
-    collect_dfs(json_value, rules.root_mut());
+    extend_rules(&mut rules, json_value);
     rules
 }
 
+/// Collect more `JSON` format rules into `rules`
+pub fn extend_rules(rules: &mut Rules, json_value: json::Value) {
+    collect_dfs(json_value, rules.root_mut());
+}
+
+/// `JSON` format rule of an atomic key, `[]` stands for elements of an array:
+/// `vim.normalModeKeyBindings[]` is the same as `{ "vim.normalModeKeyBindings": [null] }`.
+pub fn atomic_rule(key: &str) -> json::Value {
+    let Some((head, rest)) = key.split_once("[]") else {
+        return json::Value::Object(json::Map::from_iter([(key.to_owned(), json::Value::Null)]));
+    };
+    let rest = rest.strip_prefix('.').unwrap_or(rest);
+    let element = match rest {
+        "" => json::Value::Null,
+        rest => atomic_rule(rest),
+    };
+    let array = json::Value::Array(vec![element]);
+    match head {
+        "" => array,
+        head => json::Value::Object(json::Map::from_iter([(head.to_owned(), array)])),
+    }
+}
+
+/// Rules of the atomic keys listed by `atomic = [...]` of `table`
+pub fn atomic_rules(table: &toml::Table) -> anyhow::Result<Rules> {
+    let mut rules = Rules::new();
+    for key in strings(table, "atomic")? {
+        extend_rules(&mut rules, atomic_rule(&key));
+    }
+    Ok(rules)
+}
+
+/// Rules of core VSCode settings and popular extensions, compiled into the binary
+#[cfg(feature = "default-rules")]
+pub fn bundled_rules() -> anyhow::Result<Rules> {
+    let table: toml::Table = toml::from_str(include_str!("../rules/vscode.toml"))?;
+    atomic_rules(&table)
+}
+
+/// Setting keys contributed by an extension manifest (`package.json`),
+/// `contributes.configuration` may be one object or an array of them.
+pub fn manifest_keys(manifest: &json::Value) -> Vec<String> {
+    let configurations = match manifest.pointer("/contributes/configuration") {
+        Some(json::Value::Array(vs)) => vs.iter().collect(),
+        Some(v) => vec![v],
+        None => Vec::new(),
+    };
+    configurations
+        .into_iter()
+        .filter_map(|configuration| configuration.get("properties")?.as_object())
+        .flat_map(|properties| properties.keys().cloned())
+        .collect()
+}
+
+/// Setting keys of VSCode's default settings, either exported as `JSON` settings
+/// or as a schema with `properties`
+pub fn default_keys(defaults: &json::Value) -> Vec<String> {
+    let keys = match defaults.get("properties") {
+        Some(json::Value::Object(properties)) => properties,
+        _ => match defaults {
+            json::Value::Object(settings) => settings,
+            _ => return Vec::new(),
+        },
+    };
+    keys.keys().cloned().collect()
+}
+
+/// Rule of keys which are atomic as they are, no `[]` is expanded
+pub fn key_rules<Iter>(keys: Iter) -> json::Value
+where
+    Iter: IntoIterator<Item = String>,
+{
+    json::Value::Object(keys.into_iter().map(|k| (k, json::Value::Null)).collect())
+}
+
+/// Strings listed by `name = [...]` of `table`, like `atomic` keys and rule `files`
+pub fn strings(table: &toml::Table, name: &str) -> anyhow::Result<Vec<String>> {
+    let Some(value) = table.get(name) else {
+        return Ok(Vec::new());
+    };
+    let strings = value.as_array().and_then(|vs| {
+        vs.iter()
+            .map(|v| v.as_str().map(String::from))
+            .collect::<Option<Vec<_>>>()
+    });
+    strings
+        .ok_or_else(|| anyhow::anyhow!("`{}` must be an array of strings, found `{}`", name, value))
+}
+
+/// Take the `[thomson]` table out of the root config, returns its `[thomson.rules]` table
+pub fn take_inline_rules(toml_value: &mut toml::Value) -> anyhow::Result<toml::Table> {
+    let Some(config) = toml_value
+        .as_table_mut()
+        .and_then(|table| table.remove(CONFIG_KEY))
+    else {
+        return Ok(toml::Table::new());
+    };
+    match config.get("rules") {
+        None => Ok(toml::Table::new()),
+        Some(toml::Value::Table(rules)) => Ok(rules.clone()),
+        Some(_) => anyhow::bail!("`{}.rules` must be a table", CONFIG_KEY),
+    }
+}
+
 fn collect_dfs(json_value: json::Value, node: &mut rule::Node) {
     match json_value {
         serde_json::Value::Object(map) => {
             for (s, v) in map {
-                let ss: Vec<_> = s.split(".").map(|s| s.to_string()).collect();
-                let path = ss.into_iter().enumerate().map(|(i, s)| {
+                let path = rule::segments(&s).into_iter().enumerate().map(|(i, s)| {
                     (
                         if i == 0 {
                             Edge::Restarted
@@ -39,3 +145,44 @@ fn collect_dfs(json_value: json::Value, node: &mut rule::Node) {
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_manifest_keys() -> anyhow::Result<()> {
+        let manifest: json::Value = json::from_str(
+            r#"{
+                "name": "vim",
+                "contributes": {
+                    "configuration": [
+                        { "title": "Vim", "properties": { "vim.leader": {}, "vim.handleKeys": {} } },
+                        { "title": "Easymotion", "properties": { "vim.easymotion": {} } }
+                    ]
+                }
+            }"#,
+        )?;
+        assert_eq!(
+            manifest_keys(&manifest),
+            ["vim.leader", "vim.handleKeys", "vim.easymotion"]
+        );
+
+        let manifest = json::json!({ "contributes": { "configuration": { "properties": { "latex-workshop.view.pdf.viewer": {} } } } });
+        assert_eq!(manifest_keys(&manifest), ["latex-workshop.view.pdf.viewer"]);
+
+        let schema = json::json!({ "properties": { "editor.fontSize": { "type": "number" } } });
+        let settings = json::json!({ "editor.fontSize": 14 });
+        assert_eq!(default_keys(&schema), default_keys(&settings));
+        Ok(())
+    }
+
+    #[cfg(feature = "default-rules")]
+    #[test]
+    fn test_bundled_rules() -> anyhow::Result<()> {
+        let mut rules = bundled_rules()?;
+        let clashes = rules.merge(&collect_rules(json::json!({ "editor.fontSize": 14 })));
+        assert!(matches!(clashes[..], [rule::Clash::Duplicate(_)]));
+        Ok(())
+    }
+}
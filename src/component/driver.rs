@@ -1,18 +1,41 @@
-use std::ops;
+use std::{collections::BTreeSet, ops, path::PathBuf, time::Duration};
 
 use clap::Parser;
+use itertools::Itertools;
+use serde_json as json;
 
 use crate::{
-    collect::collect_rules,
-    io::{parse_json, parse_toml},
-    transform::transform_by_rules,
+    collect::{
+        atomic_rules, collect_rules, default_keys, key_rules, manifest_keys, strings,
+        take_inline_rules, CONFIG_KEY,
+    },
+    component::rule::{Clash, Rules},
+    decompile::{self, decompile_by_rules},
+    diff::{diff, merge, merge_text, normalize},
+    error,
+    format::{format, Format},
+    import,
+    io::{self, parse_json, write_atomic, Loader, OnConflict, Sources},
+    split::{split_by_policy, Policy},
+    suggest::did_you_mean,
+    transform::{is_override, transform_by_rules, unmatched_keys},
+    watch::Watcher,
 };
 
 use super::context::Context;
 
+/// Rule file used if it exists and none is given
+const DEFAULT_RULE: &str = "settings.json";
+
+/// How often watched files are polled in listening mode
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How long watched files must stay untouched before recompiling
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
 /// The main entry
 pub struct Driver {
     pub ctx: Context,
+    pub command: Option<Command>,
 }
 
 /// Pretending we have basic dependency injection...
@@ -28,46 +51,587 @@ impl Driver {
     pub fn new() -> Self {
         let args = Args::parse();
         let conf = args.toml.unwrap_or("settings.toml".to_owned());
-        let rule = args.rule.unwrap_or("settings.json".to_owned());
         Self {
-            ctx: Context::new(args.path, conf, rule, args.debugging, args.listen),
+            ctx: Context {
+                path: args.path,
+                json_path: args.rule,
+                extensions: args.extensions,
+                defaults: args.defaults,
+                toml_path: conf,
+                output: args.output,
+                merge_into: args.merge_into,
+                backups: args.backups,
+                format: args.format,
+                on_conflict: args.on_conflict,
+                debugging: args.debugging,
+                strict: args.strict,
+                listen: args.listen,
+            },
+            command: args.command,
         }
     }
 
     /// Do the job!
-    pub fn run(&self) -> anyhow::Result<String> {
-        std::env::set_current_dir(&self.path)?;
-        let json_value = parse_json(std::path::Path::new(&self.json_path))?;
-        let toml_value = parse_toml(std::path::Path::new(&self.toml_path))?;
+    pub fn run(&self) -> anyhow::Result<()> {
+        // Outputs are relative to where we are called, not to `path`
+        let cwd = std::env::current_dir()?;
+        let output = self.output.as_ref().map(|output| cwd.join(output));
+        // only compiled settings are merged, other commands never write into `--merge-into`
+        let merge_into = self.merge_into.as_ref().map(|file| cwd.join(file));
+        let target = output.clone().or(merge_into);
+        std::env::set_current_dir(&self.path)
+            .map_err(|err| error::Error::io(std::path::Path::new(&self.path), err))?;
+
+        match &self.command {
+            Some(Command::Decompile { json }) => {
+                let ans = self.decompile(std::path::Path::new(json))?;
+                return self.emit(&ans, output.as_deref());
+            }
+            Some(Command::Check) => {
+                let Some(output) = target else {
+                    anyhow::bail!(
+                        "Nothing to check, give the compiled file by `--output` or `--merge-into`"
+                    );
+                };
+                return self.check(&output);
+            }
+            Some(Command::Diff { old, new }) => {
+                let rules = self.root_rules()?;
+                let old = self.settings(std::path::Path::new(old), &rules)?;
+                let new = self.settings(std::path::Path::new(new), &rules)?;
+                for change in diff(&old, &new) {
+                    println!("{}", change);
+                }
+                return Ok(());
+            }
+            Some(Command::Import {
+                live,
+                inbox,
+                dry_run,
+            }) => {
+                // found like includes of the root config
+                let root = std::path::Path::new(&self.toml_path);
+                let inbox = match inbox {
+                    Some(inbox) => io::module_file(root.parent().unwrap_or(root), inbox),
+                    None => root.to_path_buf(),
+                };
+                return self.import(std::path::Path::new(live), &inbox, *dry_run);
+            }
+            Some(Command::Split {
+                input,
+                dir,
+                modules,
+                mapping,
+            }) => {
+                let policy = match mapping {
+                    Some(mapping) => Policy::mapping(std::path::Path::new(mapping))?,
+                    None => Policy::Prefix {
+                        dir: modules.clone(),
+                    },
+                };
+                return self.split(std::path::Path::new(input), &cwd.join(dir), &policy);
+            }
+            None => {}
+        }
+        if self.listen {
+            return self.listen(target);
+        }
+        let ans = self.compile(&mut self.loader())?;
+        self.write(ans, target.as_deref())
+    }
+
+    fn loader(&self) -> Loader {
+        Loader::new(self.on_conflict)
+    }
+
+    /// Rule files given, or the default one if it exists
+    fn rule_files(&self) -> Vec<PathBuf> {
+        if self.json_path.is_empty() {
+            let default = PathBuf::from(DEFAULT_RULE);
+            return Vec::from_iter(Some(default).filter(|path| path.exists()));
+        }
+        self.json_path.iter().map(PathBuf::from).collect()
+    }
+
+    /// Rules of a rule file: `JSON` settings, or `TOML` with `atomic = [...]`
+    fn rule_file(&self, file: &std::path::Path) -> anyhow::Result<Rules> {
+        if file.extension().is_some_and(|ext| ext == "toml") {
+            let table = self.loader().load(file)?;
+            atomic_rules(&table.as_table().cloned().unwrap_or_default())
+        } else {
+            Ok(collect_rules(parse_json(file)?))
+        }
+    }
+
+    /// Rule layers from the bottom up: bundled rules if no rule file is given, extensions,
+    /// default settings, rule files, then those declared by the root config.
+    fn rule_layers(&self, root: &mut toml::Value) -> anyhow::Result<Vec<Layer>> {
+        let mut layers = Vec::new();
+        #[cfg(feature = "default-rules")]
+        if self.rule_files().is_empty() {
+            layers.push(Layer::new(
+                "bundled rules",
+                None,
+                crate::collect::bundled_rules()?,
+            ));
+        }
+        if let Some(dir) = &self.extensions {
+            let keys = extension_keys(std::path::Path::new(dir))?;
+            layers.push(Layer::new(dir, None, collect_rules(key_rules(keys))));
+        }
+        if let Some(defaults) = &self.defaults {
+            let file = PathBuf::from(defaults);
+            let keys = default_keys(&parse_json(&file)?);
+            layers.push(Layer::new(
+                defaults,
+                Some(file),
+                collect_rules(key_rules(keys)),
+            ));
+        }
+        for file in self.rule_files() {
+            let rules = self.rule_file(&file)?;
+            layers.push(Layer::new(file.display().to_string(), Some(file), rules));
+        }
+        layers.extend(self.inline_layers(root, std::path::Path::new(&self.toml_path))?);
+        Ok(layers)
+    }
+
+    /// Rule files then atomic keys declared by `[thomson.rules]` of `root` read from `path`,
+    /// whose `[thomson]` table is removed.
+    fn inline_layers(
+        &self,
+        root: &mut toml::Value,
+        path: &std::path::Path,
+    ) -> anyhow::Result<Vec<Layer>> {
+        let inline = take_inline_rules(root)?;
+        let dir = path.parent().unwrap_or(std::path::Path::new(""));
+        let mut layers = Vec::new();
+        for file in strings(&inline, "files")? {
+            let file = dir.join(file);
+            let rules = self.rule_file(&file)?;
+            layers.push(Layer::new(file.display().to_string(), Some(file), rules));
+        }
+
+        let name = format!("`{}.rules` of {}", CONFIG_KEY, path.display());
+        layers.push(Layer::new(name, None, atomic_rules(&inline)?));
+        Ok(layers)
+    }
+
+    /// Merge `layers` into `rules` one by one, reporting clashes
+    fn merge_layers(mut rules: Rules, layers: Vec<Layer>) -> Rules {
+        for layer in layers {
+            for clash in rules.merge(&layer.rules) {
+                match clash {
+                    Clash::Duplicate(_) => log::info!("Rules of {}: {}", layer.name, clash),
+                    Clash::Contradiction(_) => log::warn!("Rules of {}: {}", layer.name, clash),
+                }
+            }
+        }
+        rules
+    }
 
-        let rules = collect_rules(json_value);
+    /// All rules, the `[thomson]` table of `root` is removed
+    fn rules(&self, root: &mut toml::Value) -> anyhow::Result<Rules> {
+        let layers = self.rule_layers(root)?;
+        Ok(Self::merge_layers(Rules::new(), layers))
+    }
+
+    /// Rules for commands which do not compile the root config
+    fn root_rules(&self) -> anyhow::Result<Rules> {
+        let root = std::path::Path::new(&self.toml_path);
+        let mut toml_value = match root.exists() {
+            true => self.loader().load(root)?,
+            false => toml::Value::Table(toml::Table::new()),
+        };
+        self.rules(&mut toml_value)
+    }
+
+    /// Compile once, `loader` remembers every module read.
+    fn compile(&self, loader: &mut Loader) -> anyhow::Result<json::Value> {
+        let mut toml_value = loader.load(std::path::Path::new(&self.toml_path))?;
+        let layers = self.rule_layers(&mut toml_value)?;
+        loader
+            .files
+            .extend(layers.iter().filter_map(|layer| layer.file.clone()));
+        let rules = Self::merge_layers(Rules::new(), layers);
         if self.debugging {
             let paths = rules.paths();
             for path in paths {
                 log::debug!("Path: {}", path);
             }
         }
-        let ans = transform_by_rules(toml_value, &rules);
-        Ok(ans.to_string())
+        self.report_unmatched(&toml_value, &rules, &loader.sources)?;
+        let ans = transform_by_rules(toml_value, &rules).map_err(error::Error::from)?;
+        Ok(ans)
+    }
+
+    /// Warn about keys matching no rule, which fail the build with `--strict`.
+    /// Nothing is reported without any rule, unless strict.
+    fn report_unmatched(
+        &self,
+        toml_value: &toml::Value,
+        rules: &Rules,
+        sources: &Sources,
+    ) -> error::Result<()> {
+        let known: BTreeSet<String> = rules
+            .paths()
+            .into_iter()
+            .filter_map(|path| path.build().into_iter().next())
+            .filter(|key| !key.is_empty())
+            .collect();
+        if known.is_empty() && !self.strict {
+            return Ok(());
+        }
+
+        let unmatched = unmatched_keys(toml_value.clone(), rules);
+        for keys in &unmatched {
+            let key = keys.iter().skip_while(|k| is_override(k)).join(".");
+            let hint = match did_you_mean(&key, known.iter().map(String::as_str)) {
+                Some(candidate) => format!(", did you mean `{}`?", candidate),
+                None => String::new(),
+            };
+            log::warn!(
+                "Key `{}` in {} matches no rule{}",
+                io::display_key(keys),
+                io::source_of(sources, keys),
+                hint
+            );
+        }
+        match unmatched.len() {
+            count if count > 0 && self.strict => Err(error::Error::Unmatched { count }),
+            _ => Ok(()),
+        }
+    }
+
+    /// With `--merge-into`, merge compiled settings into the existing `output`
+    fn merged(&self, ans: json::Value, output: &std::path::Path) -> anyhow::Result<json::Value> {
+        if self.merge_into.is_none() || !output.exists() {
+            return Ok(ans);
+        }
+        let existing = parse_json(output)?;
+        if !existing.is_object() {
+            anyhow::bail!(
+                "Cannot merge into {}, it is not an object",
+                output.display()
+            );
+        }
+        let (ans, unmanaged) = merge(existing, ans);
+        Self::report_unmanaged(output, &unmanaged);
+        Ok(ans)
+    }
+
+    /// Warn about keys of `output` which configs do not manage
+    fn report_unmanaged(output: &std::path::Path, unmanaged: &[String]) {
+        if !unmanaged.is_empty() {
+            log::warn!(
+                "{} key(s) of {} are not managed by configs: {}",
+                unmanaged.len(),
+                output.display(),
+                unmanaged.join(", ")
+            );
+        }
+    }
+
+    /// Format compiled settings and emit them.
+    /// With `--merge-into`, the existing `output` is edited in place to keep its comments.
+    fn write(&self, ans: json::Value, output: Option<&std::path::Path>) -> anyhow::Result<()> {
+        let Some(output) = output.filter(|output| self.merge_into.is_some() && output.exists())
+        else {
+            return self.emit(&format(&ans, &self.format), output);
+        };
+        let existing = io::read(output)?;
+        let merged = ans
+            .as_object()
+            .and_then(|compiled| merge_text(&existing, compiled, &self.format));
+        let Some((merged, unmanaged)) = merged else {
+            anyhow::bail!(
+                "Cannot merge into {}, it is not an object",
+                output.display()
+            );
+        };
+        Self::report_unmanaged(output, &unmanaged);
+        self.emit(&merged, Some(output))
+    }
+
+    /// Recompile and compare with `output` on disk, print what differs
+    fn check(&self, output: &std::path::Path) -> anyhow::Result<()> {
+        let compiled = self.compile(&mut self.loader())?;
+        let compiled = self.merged(compiled, output)?;
+        let changes = diff(&parse_json(output)?, &compiled);
+        if changes.is_empty() {
+            return Ok(());
+        }
+        for change in &changes {
+            println!("{}", change);
+        }
+        Err(error::Error::Outdated {
+            path: output.to_path_buf(),
+            changes: changes.len(),
+        })?
+    }
+
+    /// Edit modules so that they compile to `live` settings
+    fn import(
+        &self,
+        live: &std::path::Path,
+        inbox: &std::path::Path,
+        dry_run: bool,
+    ) -> anyhow::Result<()> {
+        let mut loader = self.loader();
+        let mut toml_value = loader.load(std::path::Path::new(&self.toml_path))?;
+        let rules = self.rules(&mut toml_value)?;
+        let owners = import::owners(toml_value.clone(), &rules);
+        let compiled = transform_by_rules(toml_value, &rules).map_err(error::Error::from)?;
+        let live = normalize(parse_json(live)?, &rules)?;
+
+        let edits = import::plan(
+            diff(&compiled, &live),
+            &owners,
+            &loader.sources,
+            &rules,
+            inbox,
+        );
+        for edit in &edits {
+            println!("{}", edit);
+        }
+        if dry_run {
+            return Ok(());
+        }
+        let included = |file: &std::path::Path| {
+            let file = std::fs::canonicalize(file).ok();
+            loader
+                .files
+                .iter()
+                .any(|f| file.is_some() && std::fs::canonicalize(f).ok() == file)
+        };
+        if edits.iter().any(|edit| edit.file == inbox) && !included(inbox) {
+            log::warn!(
+                "{} is not included by {}, new keys will not be compiled",
+                inbox.display(),
+                self.toml_path
+            );
+        }
+        import::apply(&edits)
+    }
+
+    /// Compiled settings of a `TOML` root, or normalized `JSON` settings
+    fn settings(&self, input: &std::path::Path, rules: &Rules) -> anyhow::Result<json::Value> {
+        if input.extension().is_some_and(|ext| ext == "json") {
+            return normalize(parse_json(input)?, rules);
+        }
+        let mut toml_value = self.loader().load(input)?;
+        let layers = self.inline_layers(&mut toml_value, input)?;
+        let rules = Self::merge_layers(rules.clone(), layers);
+        Ok(transform_by_rules(toml_value, &rules).map_err(error::Error::from)?)
+    }
+
+    /// Decompile `JSON` settings into `TOML` by the same rules used for compiling
+    fn decompile(&self, json: &std::path::Path) -> anyhow::Result<String> {
+        let rules = self.root_rules()?;
+        let toml_value = decompile_by_rules(parse_json(json)?, &rules)?;
+        decompile::render(&toml_value)
+    }
+
+    /// Split `input` (`TOML`, or `JSON` to be decompiled first) into modules under `dir`
+    fn split(
+        &self,
+        input: &std::path::Path,
+        dir: &std::path::Path,
+        policy: &Policy,
+    ) -> anyhow::Result<()> {
+        let toml_value = if input.extension().is_some_and(|ext| ext == "json") {
+            decompile_by_rules(parse_json(input)?, &self.root_rules()?)?
+        } else {
+            self.loader().load(input)?
+        };
+        let root_name = std::path::Path::new(&self.toml_path)
+            .file_name()
+            .unwrap_or("settings.toml".as_ref());
+        split_by_policy(toml_value, policy).write(dir, &root_name.to_string_lossy())
+    }
+
+    /// Print `ans`, or replace `output` atomically keeping backups
+    fn emit(&self, ans: &str, output: Option<&std::path::Path>) -> anyhow::Result<()> {
+        match output {
+            Some(output) => write_atomic(output, ans, self.backups)?,
+            None => print!("{}", ans),
+        }
+        Ok(())
+    }
+
+    /// Recompile whenever the root config, any included module or rule file changes.  
+    /// Errors are reported and we keep on listening.
+    fn listen(&self, output: Option<PathBuf>) -> anyhow::Result<()> {
+        let mut watcher = Watcher::new(POLL_INTERVAL, DEBOUNCE);
+        loop {
+            let mut loader = self.loader();
+            let result = self
+                .compile(&mut loader)
+                .and_then(|ans| self.write(ans, output.as_deref()));
+            let mut files = loader.files;
+            files.extend(loader.pending);
+            // Rule files are watched even if they are broken or yet to be created
+            match self.json_path.is_empty() {
+                true => files.push(PathBuf::from(DEFAULT_RULE)),
+                false => files.extend(self.json_path.iter().map(PathBuf::from)),
+            }
+
+            match result {
+                Ok(()) => {
+                    log::info!("Compiled, watching {} path(s)", files.len());
+                    watcher.reset(files);
+                }
+                Err(err) => {
+                    error::report(&err);
+                    // Keep watching what we knew, the broken module may be a new one
+                    watcher.watch(files);
+                }
+            }
+
+            let changed = watcher.wait();
+            for file in changed {
+                log::info!("Changed: {}", file.display());
+            }
+        }
+    }
+}
+
+/// Rules from one source
+struct Layer {
+    name: String,
+    /// The file to watch, if any
+    file: Option<PathBuf>,
+    rules: Rules,
+}
+
+impl Layer {
+    fn new(name: impl ToString, file: Option<PathBuf>, rules: Rules) -> Self {
+        Self {
+            name: name.to_string(),
+            file,
+            rules,
+        }
+    }
+}
+
+/// Setting keys of every extension manifest `<dir>/*/package.json`, and `<dir>/package.json`
+fn extension_keys(dir: &std::path::Path) -> anyhow::Result<Vec<String>> {
+    let entries = std::fs::read_dir(dir).map_err(|err| error::Error::io(dir, err))?;
+    let mut manifests: Vec<_> = entries
+        .filter_map(|entry| Some(entry.ok()?.path().join("package.json")))
+        .chain([dir.join("package.json")])
+        .filter(|manifest| manifest.is_file())
+        .collect();
+    manifests.sort();
+
+    let mut keys = Vec::new();
+    for manifest in manifests {
+        // One broken extension should not break the others
+        match parse_json(&manifest) {
+            Ok(manifest) => keys.extend(manifest_keys(&manifest)),
+            Err(err) => log::warn!("Skip extension manifest: {}", err),
+        }
     }
+    Ok(keys)
 }
 
 /// Parser of command line
 #[derive(clap::Parser, Debug)]
 #[command(version, about = "Thomson", long_about = None)]
 pub struct Args {
-    #[arg(short, long)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Directory of configs, inputs are relative to it while outputs are not
+    #[arg(short, long, global = true, default_value = ".")]
     pub path: String,
 
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub toml: Option<String>,
 
-    #[arg(short, long)]
-    pub rule: Option<String>,
+    /// Directory of extensions, rules are taken from their `package.json` manifests
+    #[arg(long, global = true)]
+    pub extensions: Option<String>,
 
-    #[arg(short, long, action)]
+    /// VSCode's default settings, exported as `JSON` settings or schema, taken as rules
+    #[arg(long, global = true)]
+    pub defaults: Option<String>,
+
+    /// Rule files, later ones win: `JSON` settings, or `TOML` with `atomic = [...]` [default: settings.json if it exists]
+    #[arg(short, long, global = true)]
+    pub rule: Vec<String>,
+
+    /// Fail if any key matches no rule
+    #[arg(long, global = true, action)]
+    pub strict: bool,
+
+    /// Write output to file instead of stdout, replacing it atomically
+    #[arg(short, long, global = true)]
+    pub output: Option<String>,
+
+    /// Merge into an existing settings file, replacing only the keys configs define
+    #[arg(long, global = true, conflicts_with = "output")]
+    pub merge_into: Option<String>,
+
+    /// Keep this many backups of the replaced output file, as `<output>.1` and so on
+    #[arg(long, global = true, default_value_t = 0)]
+    pub backups: usize,
+
+    /// What to do when included modules define the same key
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub on_conflict: OnConflict,
+
+    #[arg(short, long, global = true, action)]
     pub debugging: bool,
 
+    #[command(flatten)]
+    pub format: Format,
+
+    /// Keep watching all configs and recompile on change
     #[arg(short, long, action)]
     pub listen: bool,
 }
+
+/// Subcommands, compile `TOML` into `JSON` if none is given
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Decompile a `JSON` settings file into `TOML`
+    Decompile {
+        /// The `JSON` settings file
+        json: String,
+    },
+    /// Check that the file given by `--output` is up to date, print the keys which are not
+    Check,
+    /// Compare two configs (`TOML` roots or `JSON` settings) by atomic keys
+    Diff {
+        /// The config to compare from
+        old: String,
+        /// The config to compare to
+        new: String,
+    },
+    /// Import changes of live `JSON` settings back into the modules defining them
+    Import {
+        /// The live `JSON` settings file, like the one VSCode writes
+        live: String,
+        /// Module receiving keys no module defines, defaults to the root config.
+        /// Relative to the root config like includes, `.toml` may be left out
+        #[arg(long)]
+        inbox: Option<String>,
+        /// Only print what would be changed and where
+        #[arg(long, action)]
+        dry_run: bool,
+    },
+    /// Split one big config (`TOML` or `JSON`) into include modules by key prefix
+    Split {
+        /// The config to split
+        input: String,
+        /// Where to write the root config and its modules
+        dir: String,
+        /// Directory of modules, one per key prefix
+        #[arg(long, default_value = "extension")]
+        modules: String,
+        /// `TOML` file mapping key prefixes to modules, unmapped prefixes stay in the root
+        #[arg(long)]
+        mapping: Option<String>,
+    },
+}
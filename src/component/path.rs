@@ -17,7 +17,7 @@ impl Default for Path<'_> {
 
 impl fmt::Display for Path<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = self.0.iter().map(|ss| ss.into_iter().join(".")).join(", ");
+        let s = self.0.iter().map(|ss| ss.iter().join(".")).join(", ");
         write!(f, "[{}]", s)
     }
 }
@@ -138,6 +138,6 @@ impl<'s> Path<'s> {
 
     /// Flattern and return new Vec
     pub fn units(&self) -> Vec<Cow<'s, Key>> {
-        self.0.iter().flatten().map(|s| s.clone()).collect()
+        self.0.iter().flatten().cloned().collect()
     }
 }
@@ -41,6 +41,58 @@ impl Rules {
         collector
     }
 
+    /// Merge `other` into `self`, the later rules win.  
+    /// Returns what clashes with the rules already there.
+    pub fn merge(&mut self, other: &Rules) -> Vec<Clash> {
+        let mut clashes = Vec::new();
+        // Empty rules have the root as their only path
+        for path in other.paths().into_iter().filter(|path| !path.is_empty()) {
+            let shown = path.to_string();
+            let steps = path.into_iter().flat_map(|unit| {
+                unit.into_iter().enumerate().map(|(i, key)| {
+                    let edge = match i {
+                        0 => Edge::Restarted,
+                        _ => Edge::Connected,
+                    };
+                    (edge, key.into_owned())
+                })
+            });
+
+            let mut cur = self.root_mut();
+            let mut created = false;
+            let mut atomic = Path::empty();
+            for (edge, key) in steps {
+                if !created && !atomic.is_empty() && cur.is_leaf() {
+                    clashes.push(Clash::Contradiction(format!(
+                        "{} is atomic, but {} is inside it",
+                        atomic, shown
+                    )));
+                }
+                match cur.get(&key) {
+                    None => created = true,
+                    Some(next) if next.edge != edge => clashes.push(Clash::Contradiction(format!(
+                        "{} is split differently elsewhere",
+                        shown
+                    ))),
+                    Some(_) => {}
+                }
+                atomic.link(edge, Cow::Owned(key.clone()));
+                cur = cur.next(edge, key);
+            }
+
+            if !cur.is_leaf() {
+                clashes.push(Clash::Contradiction(format!(
+                    "{} is atomic, but there are keys inside it",
+                    shown
+                )));
+                cur.nexts.clear();
+            } else if !created {
+                clashes.push(Clash::Duplicate(shown));
+            }
+        }
+        clashes
+    }
+
     /// DFS
     fn collect_paths<'s>(node: &'s Node, collector: &mut Vec<Path<'s>>, path: &mut Path<'s>) {
         if node.is_leaf() {
@@ -58,18 +110,31 @@ impl Rules {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub(crate) enum Edge {
-    Connected,
-    Restarted,
+/// A rule clashing with the rules merged before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Clash {
+    /// The same atomic key again
+    Duplicate(String),
+    /// An atomic key of one rule set has keys inside it in another
+    Contradiction(String),
 }
 
-impl Default for Edge {
-    fn default() -> Self {
-        Edge::Restarted
+impl fmt::Display for Clash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clash::Duplicate(path) => write!(f, "{} is defined more than once", path),
+            Clash::Contradiction(message) => write!(f, "{}", message),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Edge {
+    Connected,
+    #[default]
+    Restarted,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Key {
     Field(String),
@@ -93,9 +158,9 @@ impl Key {
     }
 }
 
-impl Into<String> for Key {
-    fn into(self) -> String {
-        match self {
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        match key {
             Key::Field(s) => s,
             Key::Index { of, .. } => format!("{}", of),
         }
@@ -111,6 +176,24 @@ impl fmt::Display for Key {
     }
 }
 
+/// Segments of a key, split at dots.  
+/// Dots only separate identifiers (letters, digits, `-` and `_`):
+/// a key is kept whole if any segment is not one, like glob patterns `**/.git` and `*.mutable`,
+/// or language overrides `[rust]` and `[typescript][javascript]`.
+pub fn segments(key: &str) -> Vec<&str> {
+    let identifier = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    };
+    let ans: Vec<_> = key.split('.').collect();
+    if ans.iter().all(|s| identifier(s)) {
+        ans
+    } else {
+        vec![key]
+    }
+}
+
 /// Trie tree node
 /// TODO compact prefix path
 #[derive(Debug, Clone)]
@@ -132,10 +215,10 @@ impl Node {
         self.nexts.insert(key, Node::link(edge))
     }
 
-    pub(crate) fn contains_key<S: ?Sized>(&self, s: &S) -> bool
+    pub(crate) fn contains_key<S>(&self, s: &S) -> bool
     where
         Key: Borrow<S>,
-        S: Hash + Eq,
+        S: ?Sized + Hash + Eq,
     {
         self.nexts.contains_key(s)
     }
@@ -160,18 +243,18 @@ impl Node {
         this
     }
 
-    pub(crate) fn get<S: ?Sized>(&self, s: &S) -> Option<&Node>
+    pub(crate) fn get<S>(&self, s: &S) -> Option<&Node>
     where
         Key: Borrow<S>,
-        S: Hash + Eq,
+        S: ?Sized + Hash + Eq,
     {
         self.nexts.get(s)
     }
 
-    pub(crate) fn get_mut<S: ?Sized>(&mut self, s: &S) -> Option<&mut Node>
+    pub(crate) fn get_mut<S>(&mut self, s: &S) -> Option<&mut Node>
     where
         Key: Borrow<S>,
-        S: Hash + Eq,
+        S: ?Sized + Hash + Eq,
     {
         self.nexts.get_mut(s)
     }
@@ -201,4 +284,66 @@ mod test {
             dbg!(path.build());
         }
     }
+
+    #[test]
+    fn test_merge_rules() {
+        let rules = |keys: &[&str]| {
+            let mut rules = Rules::new();
+            for key in keys {
+                let path = segments(key).into_iter().enumerate().map(|(i, s)| {
+                    let edge = if i == 0 {
+                        Edge::Restarted
+                    } else {
+                        Edge::Connected
+                    };
+                    (edge, Key::field(s))
+                });
+                rules.root_mut().compact(path);
+            }
+            rules
+        };
+        let mut base = rules(&["editor.fontSize", "editor.guides"]);
+        let mut clashes = base.merge(&rules(&["editor.fontSize", "editor.guides.bracketPairs"]));
+        clashes.sort_by_key(ToString::to_string);
+        assert_eq!(
+            clashes,
+            [
+                Clash::Duplicate("[editor.fontSize]".to_owned()),
+                Clash::Contradiction(
+                    "[editor.guides] is atomic, but [editor.guides.bracketPairs] is inside it"
+                        .to_owned()
+                ),
+            ]
+        );
+        assert_eq!(base.paths().len(), 2);
+
+        let clashes = base.merge(&rules(&["editor.guides"]));
+        assert!(matches!(clashes[..], [Clash::Contradiction(_)]));
+        let mut paths: Vec<_> = base.paths().iter().map(ToString::to_string).collect();
+        paths.sort();
+        assert_eq!(paths, ["[editor.fontSize]", "[editor.guides]"]);
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(
+            segments("terminal.integrated.fontSize"),
+            ["terminal", "integrated", "fontSize"]
+        );
+        assert_eq!(
+            segments("rust-analyzer.cargo.features"),
+            ["rust-analyzer", "cargo", "features"]
+        );
+        for key in [
+            "**/.bloop",
+            "*.mutable",
+            "[rust]",
+            "[c.cpp]",
+            "editor.",
+            ".ts",
+            "",
+        ] {
+            assert_eq!(segments(key), [key]);
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! Decompile `JSON` value back into `TOML` value by rules.
+
+use serde_json as json;
+use thiserror::Error;
+
+use crate::component::rule::{self, Edge, Key, Rules};
+
+#[derive(Error, Debug)]
+pub enum DecompileErr {
+    #[error("`null` at `{0}` cannot be represented in TOML")]
+    Null(String),
+
+    #[error("Number at `{0}` cannot be represented in TOML")]
+    Number(String),
+
+    #[error("Key `{0}` is defined more than once")]
+    Conflict(String),
+}
+
+pub type DecompileResult<T> = Result<T, DecompileErr>;
+
+/// The reverse of [`crate::transform::transform_by_rules`].
+/// Dotted keys are split back into nested tables wherever the rules split them,
+/// so that the result transforms to the identical `JSON` value.
+pub fn decompile_by_rules(json_value: json::Value, rules: &Rules) -> DecompileResult<toml::Value> {
+    let mut path = Vec::new();
+    decompile_dfs(json_value, Some(rules.root()), &mut path)
+}
+
+fn decompile_dfs(
+    json_value: json::Value,
+    node: Option<&rule::Node>,
+    path: &mut Vec<String>,
+) -> DecompileResult<toml::Value> {
+    let value = match json_value {
+        json::Value::Null => return Err(DecompileErr::Null(path.join("."))),
+        json::Value::Bool(b) => toml::Value::Boolean(b),
+        json::Value::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => match n.as_f64() {
+                Some(f) if n.is_f64() => toml::Value::Float(f),
+                _ => return Err(DecompileErr::Number(path.join("."))),
+            },
+        },
+        json::Value::String(s) => toml::Value::String(s),
+        json::Value::Array(vs) => {
+            let next = node.and_then(|node| node.get(&Key::pseudo_index()));
+            let mut ans = Vec::with_capacity(vs.len());
+            for (i, v) in vs.into_iter().enumerate() {
+                path.push(i.to_string());
+                ans.push(decompile_dfs(v, next, path)?);
+                path.pop();
+            }
+            toml::Value::Array(ans)
+        }
+        json::Value::Object(map) => {
+            let mut table = toml::Table::new();
+            for (k, v) in map {
+                let (segments, next) = split_by_rules(&k, node);
+                if node.is_some() && next.is_none() {
+                    log::warn!("Key `{}` is not covered by rules", k);
+                }
+                path.push(k.clone());
+                let v = decompile_dfs(v, next, path)?;
+                insert_segments(&mut table, &segments, v)
+                    .map_err(|_| DecompileErr::Conflict(path.join(".")))?;
+                path.pop();
+            }
+            toml::Value::Table(table)
+        }
+    };
+    Ok(value)
+}
+
+/// `TOML` keys of a top-level `JSON` key, split wherever the rules split it
+pub fn split_key(key: &str, rules: &Rules) -> Vec<String> {
+    let (segments, _) = split_by_rules(key, Some(rules.root()));
+    segments.into_iter().map(String::from).collect()
+}
+
+/// Split a `JSON` key along the trie: the first segment must restart a key, all the others
+/// must be connected to it.
+/// Keys the rules do not know are kept as they are.
+fn split_by_rules<'k, 'r>(
+    key: &'k str,
+    node: Option<&'r rule::Node>,
+) -> (Vec<&'k str>, Option<&'r rule::Node>) {
+    let Some(mut cur) = node else {
+        return (vec![key], None);
+    };
+    let segments = rule::segments(key);
+    for (i, segment) in segments.iter().enumerate() {
+        let edge = if i == 0 {
+            Edge::Restarted
+        } else {
+            Edge::Connected
+        };
+        match cur.get(&Key::field(segment)) {
+            Some(next) if next.edge == edge => cur = next,
+            _ => return (vec![key], None),
+        }
+    }
+    (segments, Some(cur))
+}
+
+/// Insert value under nested tables, merging tables which already exist.
+fn insert_segments(table: &mut toml::Table, segments: &[&str], v: toml::Value) -> Result<(), ()> {
+    let (last, init) = segments.split_last().ok_or(())?;
+    let mut cur = table;
+    for segment in init {
+        let next = cur
+            .entry(segment.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        cur = next.as_table_mut().ok_or(())?;
+    }
+    match (cur.get_mut(*last), v) {
+        (None, v) => {
+            cur.insert(last.to_string(), v);
+        }
+        (Some(toml::Value::Table(old)), toml::Value::Table(new)) => {
+            for (k, v) in new {
+                insert_segments(old, &[&k], v)?;
+            }
+        }
+        _ => return Err(()),
+    }
+    Ok(())
+}
+
+/// Render `TOML` the way configs are usually written by hand:
+/// a header for each top-level table and dotted keys inside it.
+/// Arrays of tables keep their `[[array.of.tables]]` headers.
+pub fn render(toml_value: &toml::Value) -> anyhow::Result<String> {
+    let mut doc: toml_edit::DocumentMut = toml::to_string(toml_value)?.parse()?;
+    for (_, item) in doc.iter_mut() {
+        if let toml_edit::Item::Table(table) = item {
+            table.set_implicit(false);
+            dot_tables(table);
+        }
+    }
+    Ok(doc.to_string())
+}
+
+fn dot_tables(table: &mut toml_edit::Table) {
+    for (_, item) in table.iter_mut() {
+        if let toml_edit::Item::Table(table) = item {
+            if !has_array_of_tables(table) {
+                table.set_dotted(true);
+            }
+            dot_tables(table);
+        }
+    }
+}
+
+fn has_array_of_tables(table: &toml_edit::Table) -> bool {
+    table.iter().any(|(_, item)| match item {
+        toml_edit::Item::ArrayOfTables(_) => true,
+        toml_edit::Item::Table(table) => has_array_of_tables(table),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{collect::collect_rules, io, transform::transform_by_rules};
+
+    #[test]
+    fn test_round_trip() -> anyhow::Result<()> {
+        let path = std::path::Path::new("./examples/vscode/conf/settings.json");
+        let rules = collect_rules(io::parse_json(path)?);
+        let json_value = io::parse_json(path)?;
+
+        let toml_value = decompile_by_rules(json_value.clone(), &rules)?;
+        let text = render(&toml_value)?;
+        let toml_value: toml::Value = text.parse()?;
+        assert_eq!(transform_by_rules(toml_value, &rules)?, json_value);
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+//! Compare and merge `JSON` settings key by key.
+
+use std::{fmt, ops::Range};
+
+use serde_json as json;
+
+use crate::{
+    component::rule::Rules,
+    format::{format_member, Format, Indent},
+    io,
+    transform::transform_json_by_rules,
+};
+
+/// How one top-level key differs
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added {
+        key: String,
+        value: json::Value,
+    },
+    Removed {
+        key: String,
+        value: json::Value,
+    },
+    Changed {
+        key: String,
+        old: json::Value,
+        new: json::Value,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { key, value } => {
+                write!(f, "+ {}: {}", json::Value::from(key.as_str()), value)
+            }
+            Change::Removed { key, value } => {
+                write!(f, "- {}: {}", json::Value::from(key.as_str()), value)
+            }
+            Change::Changed { key, old, new } => {
+                write!(
+                    f,
+                    "~ {}: {} -> {}",
+                    json::Value::from(key.as_str()),
+                    old,
+                    new
+                )
+            }
+        }
+    }
+}
+
+/// Rewrite `JSON` settings the way they compile: `{ "editor": { "fontSize": 14 } }` becomes
+/// `{ "editor.fontSize": 14 }` if the rules say `editor.fontSize` is an atomic key.
+pub fn normalize(json_value: json::Value, rules: &Rules) -> anyhow::Result<json::Value> {
+    Ok(transform_json_by_rules(json_value, rules)?)
+}
+
+/// Changes from `old` to `new` by top-level keys, which are atomic keys of compiled settings.
+/// Values are compared semantically, the order of keys does not matter.
+/// Anything but an object counts as a single key `""`.
+pub fn diff(old: &json::Value, new: &json::Value) -> Vec<Change> {
+    let (json::Value::Object(old), json::Value::Object(new)) = (old, new) else {
+        if old == new {
+            return Vec::new();
+        }
+        return vec![Change::Changed {
+            key: String::new(),
+            old: old.clone(),
+            new: new.clone(),
+        }];
+    };
+
+    let mut ans = Vec::new();
+    for (key, value) in new {
+        match old.get(key) {
+            None => ans.push(Change::Added {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            Some(old) if old != value => ans.push(Change::Changed {
+                key: key.clone(),
+                old: old.clone(),
+                new: value.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, value) in old {
+        if !new.contains_key(key) {
+            ans.push(Change::Removed {
+                key: key.clone(),
+                value: value.clone(),
+            });
+        }
+    }
+    ans
+}
+
+/// Replace keys of `existing` settings by `compiled` ones, keeping keys the compiled settings
+/// do not manage in place.
+/// Returns the merged settings and the unmanaged keys, `compiled` wins if any is not an object.
+pub fn merge(existing: json::Value, compiled: json::Value) -> (json::Value, Vec<String>) {
+    let (mut ans, compiled) = match (existing, compiled) {
+        (json::Value::Object(ans), json::Value::Object(compiled)) => (ans, compiled),
+        (_, compiled) => return (compiled, Vec::new()),
+    };
+    let unmanaged = ans
+        .keys()
+        .filter(|key| !compiled.contains_key(*key))
+        .cloned()
+        .collect();
+    for (key, value) in compiled {
+        ans.insert(key, value);
+    }
+    (json::Value::Object(ans), unmanaged)
+}
+
+/// Like [`merge`], but edit the `JSONC` text of `existing` settings in place:
+/// managed keys are replaced where they are and new ones added after the last key,
+/// comments, unmanaged keys and indentation are kept as written.  
+/// Returns the merged text and the unmanaged keys, `None` if `existing` is not an object.
+pub fn merge_text(
+    existing: &str,
+    compiled: &json::Map<String, json::Value>,
+    format: &Format,
+) -> Option<(String, Vec<String>)> {
+    let stripped = io::strip_comments(existing).ok()?;
+    let text = stripped.as_bytes();
+    let (open, members) = members(text)?;
+    let format = Format {
+        indent: indent_of(text, open, &members).unwrap_or(format.indent),
+        ..format.clone()
+    };
+    let unmanaged = members
+        .iter()
+        .filter(|(key, _)| !compiled.contains_key(key))
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    let mut ans = String::new();
+    let mut copied = 0;
+    for (key, range) in &members {
+        if let Some(value) = compiled.get(key) {
+            ans.push_str(&existing[copied..range.start]);
+            ans.push_str(&format_member(key, value, &format));
+            copied = range.end;
+        }
+    }
+
+    let compact = format.indent.is_compact();
+    let line = match compact {
+        true => " ".to_owned(),
+        false => format!("\n{}", format.indent.unit()),
+    };
+    let added: Vec<_> = compiled
+        .iter()
+        .filter(|(key, _)| !members.iter().any(|(k, _)| k == *key))
+        .map(|(key, value)| format!("{}{}", line, format_member(key, value, &format)))
+        .collect();
+    if added.is_empty() {
+        ans.push_str(&existing[copied..]);
+        return Some((ans, unmanaged));
+    }
+
+    match members.last() {
+        // after the comma and the comment of the last member
+        Some((_, range)) => {
+            let comma = Some(skip(text, range.end)).filter(|&i| text.get(i) == Some(&b','));
+            let after = comma.map_or(range.end, |i| i + 1);
+            let end = (after..text.len())
+                .find(|&i| !matches!(text[i], b' ' | b'\t'))
+                .filter(|&i| !compact && matches!(text[i], b'\r' | b'\n'))
+                .unwrap_or(after);
+            ans.push_str(&existing[copied..range.end]);
+            if comma.is_none() {
+                ans.push(',');
+            }
+            ans.push_str(&existing[range.end..end]);
+            ans.push_str(&added.join(","));
+            if comma.is_some() {
+                ans.push(',');
+            }
+            ans.push_str(&existing[end..]);
+        }
+        None => {
+            let rest = &existing[open + 1..];
+            ans.push_str(&existing[..open + 1]);
+            ans.push_str(added.join(",").trim_start_matches(' '));
+            if format.trailing_commas && !compact {
+                ans.push(',');
+            }
+            match rest.trim_start().starts_with('}') {
+                true if !compact => ans.push_str(&format!("\n{}", rest.trim_start())),
+                true => ans.push_str(rest.trim_start()),
+                false => ans.push_str(rest),
+            }
+        }
+    }
+    Some((ans, unmanaged))
+}
+
+/// Indentation of members of the object at `open`, taken from the last one.
+/// `Spaces(0)` if it is on the line of `{`, `None` without members.
+fn indent_of(text: &[u8], open: usize, members: &[Member]) -> Option<Indent> {
+    let (_, last) = members.last()?;
+    let Some(newline) = text[open..last.start].iter().rposition(|b| *b == b'\n') else {
+        return Some(Indent::Spaces(0));
+    };
+    let line = &text[open + newline + 1..last.start];
+    match line.first() {
+        Some(b'\t') => Some(Indent::Tab),
+        _ => Some(Indent::Spaces(
+            line.iter().take_while(|b| **b == b' ').count(),
+        )),
+    }
+}
+
+/// Offset of the first non-whitespace byte from `i`
+fn skip(text: &[u8], mut i: usize) -> usize {
+    while text.get(i).is_some_and(u8::is_ascii_whitespace) {
+        i += 1;
+    }
+    i
+}
+
+/// Top-level key of `JSON` text, spanning from the key to the end of its value
+type Member = (String, Range<usize>);
+
+/// Offset of `{` of the top-level object and its members
+fn members(text: &[u8]) -> Option<(usize, Vec<Member>)> {
+    let open = skip(text, 0);
+    if text.get(open) != Some(&b'{') {
+        return None;
+    }
+
+    let mut ans = Vec::new();
+    let mut i = skip(text, open + 1);
+    while *text.get(i)? != b'}' {
+        let key_end = string_end(text, i)?;
+        let key = json::from_slice(&text[i..key_end]).ok()?;
+        let colon = skip(text, key_end);
+        if *text.get(colon)? != b':' {
+            return None;
+        }
+        let end = value_end(text, skip(text, colon + 1))?;
+        ans.push((key, i..end));
+        i = skip(text, end);
+        if *text.get(i)? == b',' {
+            i = skip(text, i + 1);
+        }
+    }
+    Some((open, ans))
+}
+
+/// Offset right after the string starting at `i`
+fn string_end(text: &[u8], i: usize) -> Option<usize> {
+    if *text.get(i)? != b'"' {
+        return None;
+    }
+    let mut j = i + 1;
+    loop {
+        match *text.get(j)? {
+            b'\\' => j += 2,
+            b'"' => return Some(j + 1),
+            _ => j += 1,
+        }
+    }
+}
+
+/// Offset right after the value starting at `i`
+fn value_end(text: &[u8], i: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut end = i;
+    let mut j = i;
+    loop {
+        match *text.get(j)? {
+            b'"' => {
+                j = string_end(text, j)?;
+                end = j;
+                continue;
+            }
+            b'}' | b']' | b',' if depth == 0 => return Some(end),
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth -= 1,
+            b if b.is_ascii_whitespace() => {
+                j += 1;
+                continue;
+            }
+            _ => {}
+        }
+        j += 1;
+        end = j;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() -> anyhow::Result<()> {
+        let old: json::Value = json::from_str(
+            r#"{ "editor.fontSize": 14, "vim.leader": "<space>", "files.exclude": { "a": true, "b": true } }"#,
+        )?;
+        let new: json::Value = json::from_str(
+            r#"{ "files.exclude": { "b": true, "a": true }, "editor.fontSize": 15, "window.zoomLevel": 1 }"#,
+        )?;
+        let changes: Vec<_> = diff(&old, &new).iter().map(ToString::to_string).collect();
+        assert_eq!(
+            changes,
+            [
+                r#"~ "editor.fontSize": 14 -> 15"#,
+                r#"+ "window.zoomLevel": 1"#,
+                r#"- "vim.leader": "<space>""#,
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+
+        let (merged, unmanaged) = merge(old, new.clone());
+        assert_eq!(unmanaged, ["vim.leader"]);
+        let changes: Vec<_> = diff(&new, &merged)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(changes, [r#"+ "vim.leader": "<space>""#]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize() -> anyhow::Result<()> {
+        let rules = crate::collect::collect_rules(json::from_str(
+            r#"{ "editor.fontSize": 14, "editor.rulers": [], "editor.defaultFormatter": "", "[rust]": { "editor.formatOnSave": true } }"#,
+        )?);
+        let dotted = json::from_str(
+            r#"{ "editor.fontSize": 14, "editor.defaultFormatter": null, "[rust]": { "editor.formatOnSave": true } }"#,
+        )?;
+        let nested = json::from_str(
+            r#"{ "[rust]": { "editor": { "formatOnSave": true } }, "editor": { "fontSize": 14, "defaultFormatter": null } }"#,
+        )?;
+        let normalized = normalize(nested, &rules)?;
+        assert!(diff(&normalize(dotted, &rules)?, &normalized).is_empty());
+        assert_eq!(
+            normalized.get("editor.defaultFormatter"),
+            Some(&json::Value::Null)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_text() -> anyhow::Result<()> {
+        let existing = r#"{
+  // fonts
+  "editor.fontSize": 14, // too small
+  "window.zoomLevel": 1, /* set by the UI */
+}
+"#;
+        let compiled = json::json!({ "editor.fontSize": 15, "vim.handleKeys": { "<C-a>": false } });
+        let compiled = compiled.as_object().unwrap();
+        let format = Format {
+            indent: Indent::Spaces(4),
+            ..Default::default()
+        };
+        let (merged, unmanaged) = merge_text(existing, compiled, &format).unwrap();
+        assert_eq!(
+            merged,
+            r#"{
+  // fonts
+  "editor.fontSize": 15, // too small
+  "window.zoomLevel": 1, /* set by the UI */
+  "vim.handleKeys": {
+    "<C-a>": false
+  },
+}
+"#
+        );
+        assert_eq!(unmanaged, ["window.zoomLevel"]);
+
+        let existing = "{\n\t\"z\": 1 // keep\n}\n";
+        let (merged, _) = merge_text(existing, compiled, &format).unwrap();
+        assert_eq!(
+            merged,
+            "{\n\t\"z\": 1, // keep\n\t\"editor.fontSize\": 15,\n\t\"vim.handleKeys\": {\n\t\t\"<C-a>\": false\n\t}\n}\n"
+        );
+        let (merged, _) = merge_text(r#"{ "z": 1 }"#, compiled, &format).unwrap();
+        assert_eq!(
+            merged,
+            r#"{ "z": 1, "editor.fontSize": 15, "vim.handleKeys": {"<C-a>":false} }"#
+        );
+        let (merged, _) = merge_text("{}", compiled, &format).unwrap();
+        assert_eq!(
+            merged,
+            "{\n    \"editor.fontSize\": 15,\n    \"vim.handleKeys\": {\n        \"<C-a>\": false\n    }\n}"
+        );
+        assert!(merge_text("[1]", compiled, &format).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! Errors with their diagnostics and exit codes.
+
+use std::{fmt, path::PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Cannot read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Cannot write {}: {source}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Cannot parse {}: {}", location.path.display(), location.message)]
+    Parse { location: Box<Location> },
+
+    #[error("Invalid include in {}: {message}", path.display())]
+    Include { path: PathBuf, message: String },
+
+    #[error("Key `{key}` is defined in both {first} and {second}")]
+    Conflict {
+        key: String,
+        first: String,
+        second: String,
+    },
+
+    #[error("{} is out of date, {changes} key(s) differ", path.display())]
+    Outdated { path: PathBuf, changes: usize },
+
+    #[error("{count} key(s) match no rule")]
+    Unmatched { count: usize },
+
+    #[error(transparent)]
+    Transform(#[from] crate::transform::TransformErr),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Where exactly parsing failed
+#[derive(Debug)]
+pub struct Location {
+    pub path: PathBuf,
+    /// 1-based
+    pub line: usize,
+    /// 1-based
+    pub column: usize,
+    pub message: String,
+    /// The offending line
+    pub text: String,
+    /// The key being defined around, if any
+    pub key: Option<String>,
+}
+
+/// Exit codes, `2` is taken by `clap` for bad arguments
+pub mod code {
+    pub const OTHER: u8 = 1;
+    pub const IO: u8 = 3;
+    pub const PARSE: u8 = 4;
+    pub const INCLUDE: u8 = 5;
+    pub const CONFLICT: u8 = 6;
+    pub const TRANSFORM: u8 = 7;
+    pub const OUTDATED: u8 = 8;
+    pub const UNMATCHED: u8 = 9;
+}
+
+impl Error {
+    pub fn io(path: &std::path::Path, source: std::io::Error) -> Self {
+        Self::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn write(path: &std::path::Path, source: std::io::Error) -> Self {
+        Self::Write {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Parse error at 1-based `line` and `column` of `content` read from `path`
+    pub fn parse(
+        path: &std::path::Path,
+        content: &str,
+        line: usize,
+        column: usize,
+        message: String,
+    ) -> Self {
+        let text = content
+            .lines()
+            .nth(line.saturating_sub(1))
+            .unwrap_or_default()
+            .to_owned();
+        let key = key_at(content, line);
+        let location = Location {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+            text,
+            key,
+        };
+        Self::Parse {
+            location: Box::new(location),
+        }
+    }
+
+    /// Parse error at byte `offset` of `content`
+    pub fn parse_at(path: &std::path::Path, content: &str, offset: usize, message: String) -> Self {
+        let before = &content[..offset.min(content.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        Self::parse(path, content, line, column, message)
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Io { .. } | Error::Write { .. } => code::IO,
+            Error::Parse { .. } => code::PARSE,
+            Error::Include { .. } => code::INCLUDE,
+            Error::Conflict { .. } => code::CONFLICT,
+            Error::Transform(_) => code::TRANSFORM,
+            Error::Outdated { .. } => code::OUTDATED,
+            Error::Unmatched { .. } => code::UNMATCHED,
+        }
+    }
+
+    /// Human readable diagnostic
+    pub fn render(&self) -> String {
+        match self {
+            Error::Parse { location } => location.to_string(),
+            err => format!("error: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = self.line.to_string();
+        let pad = " ".repeat(number.len());
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            pad,
+            self.path.display(),
+            self.line,
+            self.column
+        )?;
+        writeln!(f, "{} |", pad)?;
+        writeln!(f, "{} | {}", number, self.text)?;
+        write!(
+            f,
+            "{} | {}^",
+            pad,
+            " ".repeat(self.column.saturating_sub(1))
+        )?;
+        if let Some(key) = &self.key {
+            write!(f, "\n{} = key: {}", pad, key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Best effort: the key defined on 1-based `line`, prefixed by the last `[table]` header in Toml
+fn key_at(content: &str, line: usize) -> Option<String> {
+    let lines: Vec<_> = content.lines().take(line).collect();
+    let current = lines.last()?.trim();
+    if current.starts_with('[') {
+        return None;
+    }
+    // `key = value` in Toml, `"key": value` in Json
+    let (key, is_toml) = match (current.find('='), current.find(':')) {
+        (Some(eq), Some(colon)) if colon < eq => (&current[..colon], false),
+        (Some(eq), _) => (&current[..eq], true),
+        (None, Some(colon)) => (&current[..colon], false),
+        (None, None) => return None,
+    };
+    let key = key.trim().trim_matches('"');
+    if key.is_empty() {
+        return None;
+    }
+
+    let header = lines.iter().rev().skip(1).find_map(|l| {
+        let l = l.trim();
+        let header = l.strip_prefix("[[").or(l.strip_prefix('['))?;
+        Some(header.split(']').next()?.trim())
+    });
+    match header {
+        Some(header) if is_toml => Some(format!("{}.{}", header, key)),
+        _ => Some(key.to_owned()),
+    }
+}
+
+/// Report `err` to stderr, returns the exit code
+pub fn report(err: &anyhow::Error) -> u8 {
+    match err.downcast_ref::<Error>() {
+        Some(err) => {
+            eprintln!("{}", err.render());
+            err.code()
+        }
+        None => {
+            eprintln!("error: {:#}", err);
+            code::OTHER
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_location() {
+        let content = "[editor]\nfontSize = 1 4\n";
+        let err = Error::parse_at(
+            "a.toml".as_ref(),
+            content,
+            22,
+            "expected newline".to_owned(),
+        );
+        let Error::Parse { location } = &err else {
+            panic!("Expected parse error");
+        };
+        assert_eq!((location.line, location.column), (2, 14));
+        assert_eq!(location.key.as_deref(), Some("editor.fontSize"));
+        assert_eq!(err.code(), code::PARSE);
+        assert!(err.render().contains("--> a.toml:2:14"));
+    }
+}
@@ -0,0 +1,55 @@
+//! Temporary modules for tests.
+
+use std::{
+    ops,
+    path::{Path, PathBuf},
+};
+
+/// A fresh directory, removed when dropped even if the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Write `text` to `file` under the directory, creating the directories on the way
+    pub fn write(&self, file: &str, text: &str) -> std::io::Result<PathBuf> {
+        let path = self.0.join(file);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, text)?;
+        Ok(path)
+    }
+}
+
+impl ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write modules like `("sub/a", "a = 1")` into a fresh directory as `.toml` files.
+/// `$DIR` stands for the directory, for includes spelled absolutely.
+pub fn modules(name: &str, files: &[(&str, &str)]) -> std::io::Result<TempDir> {
+    let dir = std::env::temp_dir().join(format!("thomson-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let ans = TempDir(dir);
+    for (file, text) in files {
+        let text = text.replace("$DIR", &ans.to_string_lossy());
+        ans.write(&format!("{}.toml", file), &text)?;
+    }
+    Ok(ans)
+}
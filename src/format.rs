@@ -0,0 +1,243 @@
+//! Pretty print `JSON` the way `settings.json` is written by hand.
+
+use std::{fmt::Write, str::FromStr};
+
+use serde_json as json;
+
+use crate::component::rule::segments;
+
+/// One level of indentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indent {
+    /// `0` prints everything on one line
+    Spaces(usize),
+    Tab,
+}
+
+impl FromStr for Indent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" => Ok(Self::Tab),
+            n => n
+                .parse()
+                .map(Self::Spaces)
+                .map_err(|_| format!("expected a number of spaces or `tab`, found `{}`", n)),
+        }
+    }
+}
+
+impl Indent {
+    /// One level as text
+    pub fn unit(&self) -> String {
+        match self {
+            Indent::Spaces(n) => " ".repeat(*n),
+            Indent::Tab => "\t".to_owned(),
+        }
+    }
+
+    /// Whether everything is on one line
+    pub fn is_compact(&self) -> bool {
+        *self == Indent::Spaces(0)
+    }
+}
+
+/// Order of keys in objects
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    /// As they appear in `TOML`, included modules first
+    #[default]
+    Source,
+    Lexicographic,
+}
+
+/// Formatting options of the compiled `JSON`
+#[derive(clap::Args, Debug, Clone)]
+pub struct Format {
+    /// Indentation: a number of spaces (`0` for one line) or `tab`
+    #[arg(long, default_value = "4")]
+    pub indent: Indent,
+
+    /// Order of keys in objects
+    #[arg(long, value_enum, default_value_t)]
+    pub order: Order,
+
+    /// Group top-level keys by their first segment, separated by blank lines
+    #[arg(long, action)]
+    pub group: bool,
+
+    /// Add trailing commas, only for `JSONC` targets like VSCode's `settings.json`
+    #[arg(long, action)]
+    pub trailing_commas: bool,
+
+    /// Do not end the output with a newline
+    #[arg(long, action)]
+    pub no_final_newline: bool,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self {
+            indent: Indent::Spaces(4),
+            order: Order::Source,
+            group: false,
+            trailing_commas: false,
+            no_final_newline: false,
+        }
+    }
+}
+
+/// Format `value` by `format`
+pub fn format(value: &json::Value, format: &Format) -> String {
+    let mut sorted = None;
+    if format.order == Order::Lexicographic {
+        sorted.insert(value.clone()).sort_all_objects();
+    }
+    let value = sorted.as_ref().unwrap_or(value);
+
+    let mut ans = String::new();
+    match value {
+        json::Value::Object(map) if format.group && !format.indent.is_compact() => {
+            write_groups(&mut ans, map, format)
+        }
+        value => write_value(&mut ans, value, 0, format),
+    }
+    if !format.no_final_newline {
+        ans.push('\n');
+    }
+    ans
+}
+
+/// Format a top-level `key` and its `value` by `format`, as a member of an existing object
+pub fn format_member(key: &str, value: &json::Value, format: &Format) -> String {
+    let mut sorted = None;
+    if format.order == Order::Lexicographic {
+        sorted.insert(value.clone()).sort_all_objects();
+    }
+    let value = sorted.as_ref().unwrap_or(value);
+
+    let mut ans = format!("{}: ", json::Value::from(key));
+    write_value(&mut ans, value, 1, format);
+    ans
+}
+
+/// The group of a top-level key like `editor.fontSize`
+fn group_of(key: &str) -> &str {
+    segments(key)[0]
+}
+
+/// Keys of the same group are put together, groups are ordered by their first key.
+fn write_groups(ans: &mut String, map: &json::Map<String, json::Value>, format: &Format) {
+    let mut groups: Vec<(&str, Vec<(&String, &json::Value)>)> = Vec::new();
+    for (k, v) in map {
+        let group = group_of(k);
+        match groups.iter_mut().find(|(g, _)| *g == group) {
+            Some((_, entries)) => entries.push((k, v)),
+            None => groups.push((group, vec![(k, v)])),
+        }
+    }
+    if groups.is_empty() {
+        ans.push_str("{}");
+        return;
+    }
+
+    let unit = format.indent.unit();
+    let total = map.len();
+    let mut i = 0;
+    ans.push('{');
+    for (n, (_, entries)) in groups.iter().enumerate() {
+        if n > 0 {
+            ans.push('\n');
+        }
+        for (k, v) in entries {
+            i += 1;
+            write!(ans, "\n{}{}: ", unit, json::Value::from(k.as_str())).unwrap();
+            write_value(ans, v, 1, format);
+            if i < total || format.trailing_commas {
+                ans.push(',');
+            }
+        }
+    }
+    ans.push_str("\n}");
+}
+
+fn write_value(ans: &mut String, value: &json::Value, depth: usize, format: &Format) {
+    let compact = format.indent.is_compact();
+    let unit = format.indent.unit();
+    let items: Vec<(Option<&String>, &json::Value)> = match value {
+        json::Value::Array(vs) if !vs.is_empty() => vs.iter().map(|v| (None, v)).collect(),
+        json::Value::Object(map) if !map.is_empty() => {
+            map.iter().map(|(k, v)| (Some(k), v)).collect()
+        }
+        value => {
+            ans.push_str(&value.to_string());
+            return;
+        }
+    };
+
+    let (open, close) = match value {
+        json::Value::Array(_) => ('[', ']'),
+        _ => ('{', '}'),
+    };
+    ans.push(open);
+    let total = items.len();
+    for (i, (k, v)) in items.into_iter().enumerate() {
+        if !compact {
+            write!(ans, "\n{}", unit.repeat(depth + 1)).unwrap();
+        }
+        if let Some(k) = k {
+            let separator = if compact { ":" } else { ": " };
+            write!(ans, "{}{}", json::Value::from(k.as_str()), separator).unwrap();
+        }
+        write_value(ans, v, depth + 1, format);
+        if i + 1 < total || (format.trailing_commas && !compact) {
+            ans.push(',');
+        }
+    }
+    if !compact {
+        write!(ans, "\n{}", unit.repeat(depth)).unwrap();
+    }
+    ans.push(close);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() -> anyhow::Result<()> {
+        let value: json::Value = json::from_str(
+            r#"{ "editor.fontSize": 14, "files.exclude": {}, "editor.rulers": [80, 100] }"#,
+        )?;
+        assert_eq!(
+            format(
+                &value,
+                &Format {
+                    indent: Indent::Spaces(0),
+                    ..Default::default()
+                }
+            ),
+            format!("{}\n", value)
+        );
+
+        let format = Format {
+            indent: "tab".parse().map_err(anyhow::Error::msg)?,
+            order: Order::Source,
+            group: true,
+            trailing_commas: true,
+            no_final_newline: true,
+        };
+        let expected = "{\n\t\"editor.fontSize\": 14,\n\t\"editor.rulers\": [\n\t\t80,\n\t\t100,\n\t],\n\n\t\"files.exclude\": {},\n}";
+        assert_eq!(super::format(&value, &format), expected);
+
+        let format = Format {
+            indent: Indent::Spaces(0),
+            order: Order::Lexicographic,
+            ..Default::default()
+        };
+        let expected = r#"{"editor.fontSize":14,"editor.rulers":[80,100],"files.exclude":{}}"#;
+        assert_eq!(super::format(&value, &format).trim_end(), expected);
+        Ok(())
+    }
+}
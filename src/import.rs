@@ -0,0 +1,333 @@
+//! Import changes of live `JSON` settings back into the `TOML` modules defining them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+};
+
+use serde_json as json;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+use crate::{
+    component::rule::Rules,
+    decompile::{decompile_by_rules, split_key},
+    diff::Change,
+    error::Error,
+    io::{self, Sources},
+    transform::owners_by_rules,
+};
+
+/// One change to make in one module
+#[derive(Debug)]
+pub struct Edit {
+    pub file: PathBuf,
+    /// Key path inside `file`
+    pub path: Vec<String>,
+    /// `None` to remove the key
+    pub value: Option<toml::Value>,
+    pub change: Change,
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.change, self.file.display())
+    }
+}
+
+/// `TOML` key path defining each top-level `JSON` key
+pub fn owners(toml_value: toml::Value, rules: &Rules) -> HashMap<String, Vec<String>> {
+    let mut ans = HashMap::new();
+    for (json_key, keys) in owners_by_rules(toml_value, rules) {
+        ans.entry(json_key).or_insert(keys);
+    }
+    ans
+}
+
+/// Where to make `changes` from compiled to live settings:
+/// changed and removed keys in the modules defining them, added keys in `inbox`.
+pub fn plan(
+    changes: Vec<Change>,
+    owners: &HashMap<String, Vec<String>>,
+    sources: &Sources,
+    rules: &Rules,
+    inbox: &std::path::Path,
+) -> Vec<Edit> {
+    let mut ans = Vec::new();
+    for change in changes {
+        let (key, value) = match &change {
+            Change::Added { key, value } => {
+                let Some(value) = decompile(key, value, rules) else {
+                    continue;
+                };
+                ans.push(Edit {
+                    file: inbox.to_path_buf(),
+                    path: split_key(key, rules),
+                    value: Some(value),
+                    change,
+                });
+                continue;
+            }
+            Change::Removed { key, .. } => (key.clone(), None),
+            Change::Changed { key, new, .. } => match decompile(key, new, rules) {
+                Some(new) => (key.clone(), Some(new)),
+                None => continue,
+            },
+        };
+
+        let mut found = owners
+            .get(&key)
+            .into_iter()
+            .flat_map(|path| io::sources_under(sources, path));
+        let Some((_, source)) = found.next() else {
+            log::warn!("Cannot find the module defining `{}`, skipped", key);
+            continue;
+        };
+        if found.any(|(_, other)| other.file != source.file) {
+            log::warn!(
+                "Key `{}` is defined across several modules, editing {}",
+                key,
+                source.file.display()
+            );
+        }
+        ans.push(Edit {
+            file: source.file.clone(),
+            path: owners[&key][source.depth..].to_vec(),
+            value,
+            change,
+        });
+    }
+    ans
+}
+
+/// `value` of the top-level `key` in `TOML`, keys of objects split into tables by the rules
+fn decompile(key: &str, value: &json::Value, rules: &Rules) -> Option<toml::Value> {
+    let json_value = json::Value::Object(json::Map::from_iter([(key.to_owned(), value.clone())]));
+    let toml_value = decompile_by_rules(json_value, rules)
+        .inspect_err(|err| log::warn!("Cannot import `{}`: {}", key, err))
+        .ok()?;
+    split_key(key, rules)
+        .iter()
+        .try_fold(toml_value, |value, segment| match value {
+            toml::Value::Table(mut table) => table.remove(segment),
+            _ => None,
+        })
+}
+
+/// Make all `edits`, keeping comments and formatting of every module
+pub fn apply(edits: &[Edit]) -> anyhow::Result<()> {
+    let mut files: BTreeMap<&std::path::Path, Vec<&Edit>> = BTreeMap::new();
+    for edit in edits {
+        files.entry(&edit.file).or_default().push(edit);
+    }
+
+    for (file, edits) in files {
+        let mut doc = DocumentMut::new();
+        if file.exists() {
+            let content = std::fs::read_to_string(file).map_err(|err| Error::io(file, err))?;
+            doc = content.parse().map_err(|err: toml_edit::TomlError| {
+                let offset = err.span().map(|span| span.start).unwrap_or_default();
+                Error::parse_at(file, &content, offset, err.message().to_owned())
+            })?;
+        }
+        for edit in edits {
+            let done = match &edit.value {
+                Some(value) => set(doc.as_table_mut(), &edit.path, value, 0),
+                None => remove(doc.as_table_mut(), &edit.path),
+            };
+            if !done {
+                log::warn!(
+                    "Cannot edit `{}` in {}",
+                    io::display_key(&edit.path),
+                    file.display()
+                );
+            }
+        }
+        io::write_atomic(file, &doc.to_string(), 0)?;
+    }
+    Ok(())
+}
+
+/// Top-level tables get a header, nested ones are dotted
+fn new_table(depth: usize) -> Item {
+    let mut table = toml_edit::Table::new();
+    table.set_dotted(depth > 0);
+    Item::Table(table)
+}
+
+fn set(table: &mut dyn TableLike, path: &[String], value: &toml::Value, depth: usize) -> bool {
+    let Some((key, rest)) = path.split_first() else {
+        return false;
+    };
+    if rest.is_empty() {
+        return replace(table, key, value, depth);
+    }
+    if !table.get(key).is_some_and(Item::is_table_like) {
+        table.insert(key, new_table(depth));
+    }
+    match table.get_mut(key).and_then(Item::as_table_like_mut) {
+        Some(next) => set(next, rest, value, depth + 1),
+        None => false,
+    }
+}
+
+/// Replace `key` of `table` by `value`, tables are synced key by key to keep their formatting
+fn replace(table: &mut dyn TableLike, key: &str, value: &toml::Value, depth: usize) -> bool {
+    if let toml::Value::Table(map) = value {
+        if !map.is_empty() {
+            if !table.get(key).is_some_and(Item::is_table_like) {
+                table.insert(key, new_table(depth));
+            }
+            let Some(next) = table.get_mut(key).and_then(Item::as_table_like_mut) else {
+                return false;
+            };
+            let stale: Vec<_> = next
+                .iter()
+                .map(|(k, _)| k.to_owned())
+                .filter(|k| !map.contains_key(k))
+                .collect();
+            for k in stale {
+                next.remove(&k);
+            }
+            return map.iter().all(|(k, v)| replace(next, k, v, depth + 1));
+        }
+    }
+
+    let mut new = to_toml(value);
+    match table.get_mut(key) {
+        Some(Item::Value(old)) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+        _ => {
+            table.insert(key, Item::Value(new));
+        }
+    }
+    true
+}
+
+fn remove(table: &mut dyn TableLike, path: &[String]) -> bool {
+    match path {
+        [] => false,
+        [key] => table.remove(key).is_some(),
+        [key, rest @ ..] => {
+            let Some(next) = table.get_mut(key).and_then(Item::as_table_like_mut) else {
+                return false;
+            };
+            let removed = remove(next, rest);
+            if removed && next.is_empty() {
+                table.remove(key);
+            }
+            removed
+        }
+    }
+}
+
+fn to_toml(value: &toml::Value) -> toml_edit::Value {
+    match value {
+        toml::Value::String(s) => s.as_str().into(),
+        toml::Value::Integer(i) => (*i).into(),
+        toml::Value::Float(f) => (*f).into(),
+        toml::Value::Boolean(b) => (*b).into(),
+        toml::Value::Datetime(datetime) => (*datetime).into(),
+        toml::Value::Array(vs) => vs.iter().map(to_toml).collect::<toml_edit::Array>().into(),
+        toml::Value::Table(map) => map
+            .iter()
+            .map(|(k, v)| (k.as_str(), to_toml(v)))
+            .collect::<toml_edit::InlineTable>()
+            .into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collect::collect_rules, diff::diff, fixture::modules, transform::transform_by_rules,
+    };
+
+    #[test]
+    fn test_import() -> anyhow::Result<()> {
+        let dir = modules(
+            "import",
+            &[
+                (
+                    "settings",
+                    "include = [{ path = \"vim\", under = \"vim\" }]\n\n[editor]\n# Big\nfontSize = 14 # px\nwordWrap = \"on\"\n\n['[rust]']\neditor.defaultFormatter = \"rust-analyzer\" # kept\neditor.formatOnSave = true\n",
+                ),
+                (
+                    "vim",
+                    "leader = \"<space>\"\nhandleKeys = { \"<C-a>\" = false }\n",
+                ),
+            ],
+        )?;
+        let root = dir.join("settings.toml");
+        let vim = dir.join("vim.toml");
+
+        let rules = collect_rules(json::from_str(
+            r#"{ "editor.fontSize": 1, "editor.wordWrap": "", "editor.guides.bracketPairs": "", "vim.leader": "", "vim.handleKeys": { "<C-a>": true }, "[rust]": { "editor.defaultFormatter": "", "editor.formatOnSave": true } }"#,
+        )?);
+        let mut loader = io::Loader::default();
+        let toml_value = loader.load(&root)?;
+        let owners = owners(toml_value.clone(), &rules);
+        let compiled = transform_by_rules(toml_value, &rules)?;
+        let live = json::from_str(
+            r#"{ "editor.fontSize": 15, "vim.leader": "<space>", "vim.handleKeys": { "<C-a>": true }, "editor.guides.bracketPairs": "active", "[rust]": { "editor.defaultFormatter": "rust-analyzer", "editor.formatOnSave": false } }"#,
+        )?;
+
+        let edits = plan(
+            diff(&compiled, &live),
+            &owners,
+            &loader.sources,
+            &rules,
+            &root,
+        );
+        apply(&edits)?;
+        assert_eq!(
+            std::fs::read_to_string(&root)?,
+            "include = [{ path = \"vim\", under = \"vim\" }]\n\n[editor]\n# Big\nfontSize = 15 # px\nguides.bracketPairs = \"active\"\n\n['[rust]']\neditor.defaultFormatter = \"rust-analyzer\" # kept\neditor.formatOnSave = false\n",
+        );
+        assert_eq!(
+            std::fs::read_to_string(&vim)?,
+            "leader = \"<space>\"\nhandleKeys = { \"<C-a>\" = true }\n",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_quoted_keys() -> anyhow::Result<()> {
+        let dir = modules(
+            "import-quoted",
+            &[(
+                "settings",
+                "[editor]\n\"guides.bracketPairs\" = \"active\" # quoted\n",
+            )],
+        )?;
+        let root = dir.join("settings.toml");
+
+        let rules = collect_rules(json::from_str(r#"{ "editor.guides.bracketPairs": "" }"#)?);
+        let mut loader = io::Loader::default();
+        let toml_value = loader.load(&root)?;
+        let owners = owners(toml_value.clone(), &rules);
+        assert_eq!(
+            owners["editor.guides.bracketPairs"],
+            ["editor", "guides.bracketPairs"]
+        );
+
+        let compiled = transform_by_rules(toml_value, &rules)?;
+        let live = json::json!({ "editor.guides.bracketPairs": false });
+        let edits = plan(
+            diff(&compiled, &live),
+            &owners,
+            &loader.sources,
+            &rules,
+            &root,
+        );
+        apply(&edits)?;
+        assert_eq!(
+            std::fs::read_to_string(&root)?,
+            "[editor]\n\"guides.bracketPairs\" = false # quoted\n",
+        );
+        Ok(())
+    }
+}
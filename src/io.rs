@@ -1,78 +1,836 @@
-use std::str::FromStr;
-use thiserror::Error;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
 
 use serde_json::{self as json};
 
-/// Format error
-#[derive(Error, Debug)]
-pub enum FmtErr<F: FromStr>
-where
-    F::Err: std::fmt::Debug + std::error::Error + 'static,
-{
-    #[error("Reading IO error: {0:?}")]
-    ReadErr(#[from] std::io::Error),
+use crate::error::{Error, Result};
 
-    #[error("Parsing error: {0:?}")]
-    ParseErr(F::Err), // `F::Err` may be Self again, therefore we cannot directly `#[from]` here :(
+pub fn read(path: &std::path::Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|err| Error::io(path, err))
 }
 
-pub type FmtResult<Fmt> = Result<Fmt, FmtErr<Fmt>>;
+/// Parse Toml file into `T`, like a [`toml::Table`] which keeps keys in the order they are written
+pub fn parse_toml<T: serde::de::DeserializeOwned>(path: &std::path::Path) -> Result<T> {
+    let content = read(path)?;
+    toml::from_str(&content).map_err(|err| {
+        let offset = err.span().map(|span| span.start).unwrap_or_default();
+        Error::parse_at(path, &content, offset, err.message().to_owned())
+    })
+}
+
+/// What to do when two modules define the same key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Fail
+    Error,
+    /// Let the later definition win, but tell
+    #[default]
+    Warn,
+    /// Let the later definition win
+    Override,
+    /// Let the first definition win
+    KeepFirst,
+}
+
+/// Where a leaf comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub file: PathBuf,
+    /// Number of leading keys added by `under`, the rest is the key path inside `file`
+    pub depth: usize,
+}
+
+/// Where every leaf comes from, by key path
+pub type Sources = BTreeMap<Vec<String>, Source>;
+
+/// Toml loader which follows `include`s.  
+/// Remembers every module it has read, so that one can watch them later.
+///
+/// Include paths are relative to the including file,
+/// or to the root config if prefixed with `root:`.
+/// Globs like `extension/*` and directories like `conf.d/` include every `.toml` file matched,
+/// in sorted order.
+///
+/// Includes are merged in order, then the including file on top of them:
+/// the including file overrides its includes, later includes override earlier ones.
+/// A module included more than once under the same key prefix is only loaded the first time.
+#[derive(Debug, Default)]
+pub struct Loader {
+    pub files: Vec<PathBuf>,
+    /// Paths which may bring new modules later:
+    /// directories searched by glob includes and missing optional modules
+    pub pending: Vec<PathBuf>,
+    pub sources: Sources,
+    pub on_conflict: OnConflict,
+    /// Directory of the root config
+    root: PathBuf,
+    /// Modules being loaded, canonical paths along with paths as included
+    stack: Vec<(PathBuf, PathBuf)>,
+    /// Canonical paths of modules loaded, along with the key prefix they are mounted under
+    loaded: HashSet<(PathBuf, Vec<String>)>,
+}
+
+/// Prefix of include paths relative to the root config
+const ROOT_PREFIX: &str = "root:";
+
+/// File of `module` relative to `base`, the `.toml` extension may be left out
+pub fn module_file(base: &std::path::Path, module: &str) -> PathBuf {
+    let module = module.strip_suffix(".toml").unwrap_or(module);
+    base.join(format!("{}.toml", module))
+}
+
+/// An entry of `include`, either just the path or a table like
+/// `{ path = "work", optional = true, when = { env = "WORK" }, under = "vim" }`
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Include {
+    path: String,
+    /// Skip silently if missing
+    #[serde(default)]
+    optional: bool,
+    /// Include only if all conditions hold
+    #[serde(default)]
+    when: When,
+    /// Dotted key to mount the module under
+    under: Option<String>,
+}
+
+impl Include {
+    /// Read an entry of `include`, tells what is wrong if it is invalid.
+    fn parse(value: toml::Value) -> std::result::Result<Self, String> {
+        match value {
+            toml::Value::String(path) => Ok(Self {
+                path,
+                ..Default::default()
+            }),
+            toml::Value::Table(_) => {
+                let display = value.to_string();
+                value
+                    .try_into()
+                    .map_err(|err: toml::de::Error| format!("`{}`: {}", display, err.message()))
+            }
+            value => Err(format!("`{}`: expected a path or a table", value)),
+        }
+    }
 
-pub(crate) fn parse<Format: FromStr>(path: &std::path::Path) -> FmtResult<Format>
-where
-    Format::Err: std::error::Error,
-{
-    let file = std::fs::read_to_string(path)?;
-    let conf: Format = file.parse().map_err(|err| FmtErr::ParseErr(err))?;
-    Ok(conf)
+    /// Keys of `under`, dotted and quoted as in Toml
+    fn under(&self) -> std::result::Result<Vec<String>, String> {
+        let Some(under) = &self.under else {
+            return Ok(Vec::new());
+        };
+        let keys = toml_edit::Key::parse(under)
+            .map_err(|err| format!("`{}`: {}", under, err.message()))?;
+        Ok(keys.into_iter().map(|k| k.get().to_owned()).collect())
+    }
 }
 
-/// Parse Toml file into [`toml::Value`] whose `Table` is a `BTreeMap<String, toml::Value>`
-pub fn parse_toml(path: &std::path::Path) -> FmtResult<toml::Value> {
-    let mut tv = parse(path)?;
-    if let toml::Value::Table(ref mut table) = &mut tv {
-        let includes = if table.contains_key("include") {
-            table.remove("include")
+/// Conditions of an include
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct When {
+    /// Environment variable which must be set and non-empty
+    env: Option<String>,
+    /// Operating system which must be running, as in [`std::env::consts::OS`]
+    os: Option<String>,
+}
+
+impl When {
+    fn holds(&self) -> bool {
+        let env = self
+            .env
+            .as_ref()
+            .is_none_or(|env| std::env::var_os(env).is_some_and(|v| !v.is_empty()));
+        let os = self.os.as_ref().is_none_or(|os| os == std::env::consts::OS);
+        env && os
+    }
+}
+
+impl Loader {
+    pub fn new(on_conflict: OnConflict) -> Self {
+        Self {
+            on_conflict,
+            ..Default::default()
+        }
+    }
+
+    /// Parse Toml file and merge all its includes recursively
+    pub fn load(&mut self, path: &std::path::Path) -> Result<toml::Value> {
+        self.root = path
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default();
+        self.stack.clear();
+        self.loaded.clear();
+        let (table, sources) = self.load_module(path, &[])?;
+        self.sources = sources;
+        Ok(toml::Value::Table(table))
+    }
+
+    /// Load module `path` mounted under `prefix` from the root
+    fn load_module(
+        &mut self,
+        path: &std::path::Path,
+        prefix: &[String],
+    ) -> Result<(toml::Table, Sources)> {
+        let canonical = std::fs::canonicalize(path).map_err(|err| Error::io(path, err))?;
+        if let Some(i) = self.stack.iter().position(|(c, _)| *c == canonical) {
+            let chain = self.stack[i..]
+                .iter()
+                .map(|(_, p)| p.display().to_string())
+                .chain([path.display().to_string()])
+                .collect::<Vec<_>>();
+            let (_, from) = self.stack.last().cloned().unwrap_or_default();
+            return Err(Error::Include {
+                path: from,
+                message: format!("cycle {}", chain.join(" -> ")),
+            });
+        }
+        if !self.loaded.insert((canonical.clone(), prefix.to_vec())) {
+            return Ok((toml::Table::new(), Sources::new()));
+        }
+        if !self.files.iter().any(|file| file == path) {
+            self.files.push(path.to_path_buf());
+        }
+        self.stack.push((canonical, path.to_path_buf()));
+        let mut table: toml::Table = parse_toml(path)?;
+
+        let mut ans = toml::Table::new();
+        let mut sources = Sources::new();
+        if let Some(toml::Value::Array(includes)) = table.remove("include") {
+            for include in includes {
+                let invalid = |message| Error::Include {
+                    path: path.to_path_buf(),
+                    message,
+                };
+                let include = Include::parse(include).map_err(invalid)?;
+                if !include.when.holds() {
+                    log::debug!("Skip include `{}`: condition does not hold", include.path);
+                    continue;
+                }
+                let under = include.under().map_err(invalid)?;
+                let mounted = [prefix, &under].concat();
+                for module in self.resolve(path, &include)? {
+                    let (inner, inner_sources) = self.load_module(&module, &mounted)?;
+                    let (inner, inner_sources) = mount(inner, inner_sources, &under);
+                    self.merge(
+                        &mut ans,
+                        &mut sources,
+                        inner,
+                        &inner_sources,
+                        &mut Vec::new(),
+                    )?;
+                }
+            }
+        }
+
+        let mut own_sources = Sources::new();
+        collect_sources(&table, path, &mut own_sources, &mut Vec::new());
+        self.merge(&mut ans, &mut sources, table, &own_sources, &mut Vec::new())?;
+        self.stack.pop();
+        Ok((ans, sources))
+    }
+
+    /// Paths of modules included by file `from`
+    fn resolve(&mut self, from: &std::path::Path, include: &Include) -> Result<Vec<PathBuf>> {
+        let module = include.path.as_str();
+        let (base, module) = match module.strip_prefix(ROOT_PREFIX) {
+            Some(module) => (self.root.as_path(), module),
+            None => (from.parent().unwrap_or(std::path::Path::new("")), module),
+        };
+        let pattern = if module.ends_with('/') {
+            format!("{}*", module)
+        } else if module.contains(['*', '?', '[']) {
+            // `.toml` is added back below
+            module.strip_suffix(".toml").unwrap_or(module).to_owned()
         } else {
-            None
+            let path = module_file(base, module);
+            if path.exists() {
+                return Ok(vec![path]);
+            }
+            if !include.optional {
+                return Err(Error::Include {
+                    path: from.to_path_buf(),
+                    message: format!("`{}` not found at {}", include.path, path.display()),
+                });
+            }
+            log::debug!("Skip include `{}`: optional and missing", module);
+            self.pending.push(path);
+            return Ok(Vec::new());
         };
 
-        if let Some(toml::Value::Array(includes)) = includes {
-            for module in includes {
-                if let Some(path) = module.as_str() {
-                    let path = format!("{}.toml", path);
-                    // dbg!(&path);
-                    let inner = parse_toml(&std::path::Path::new(&path))?;
-                    if let toml::Value::Table(t) = inner {
-                        for (k, v) in t {
-                            insert_toml_value(table, k, v);
+        let dir = pattern
+            .split('/')
+            .take_while(|part| !part.contains(['*', '?', '[']))
+            .fold(base.to_path_buf(), |dir, part| dir.join(part));
+        self.pending.push(dir);
+
+        let base = glob::Pattern::escape(&base.to_string_lossy());
+        let pattern = if base.is_empty() {
+            format!("{}.toml", pattern)
+        } else {
+            format!("{}/{}.toml", base, pattern)
+        };
+        let invalid = |err: glob::PatternError| Error::Include {
+            path: from.to_path_buf(),
+            message: format!("`{}`: {}", include.path, err),
+        };
+        let from = std::fs::canonicalize(from).map_err(|err| Error::io(from, err))?;
+        let mut paths = Vec::new();
+        for path in glob::glob(&pattern)
+            .map_err(invalid)?
+            .filter_map(|p| p.ok())
+        {
+            if !path.is_file() {
+                continue;
+            }
+            // never include oneself by a glob
+            let canonical = std::fs::canonicalize(&path).map_err(|err| Error::io(&path, err))?;
+            if canonical != from {
+                paths.push(path);
+            }
+        }
+        if paths.is_empty() && !include.optional {
+            log::warn!("Include `{}` matches no module", module);
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Merge `new` into `ans`, `new` has higher precedence.
+    fn merge(
+        &self,
+        ans: &mut toml::Table,
+        ans_sources: &mut Sources,
+        new: toml::Table,
+        new_sources: &Sources,
+        path: &mut Vec<String>,
+    ) -> Result<()> {
+        for (k, v) in new {
+            path.push(k.clone());
+            match (ans.get_mut(&k), v) {
+                (None, v) => {
+                    move_sources(ans_sources, new_sources, path);
+                    ans.insert(k, v);
+                }
+                (Some(toml::Value::Table(old)), toml::Value::Table(v)) => {
+                    self.merge(old, ans_sources, v, new_sources, path)?;
+                }
+                (Some(old), v) => {
+                    let first = source_of(ans_sources, path);
+                    let second = source_of(new_sources, path);
+                    let key = display_key(path);
+                    match self.on_conflict {
+                        OnConflict::Error => {
+                            return Err(Error::Conflict { key, first, second });
+                        }
+                        OnConflict::Warn => {
+                            log::warn!(
+                                "Key `{}` is defined in both {} and {}, using the latter",
+                                key,
+                                first,
+                                second
+                            );
+                        }
+                        OnConflict::Override => {}
+                        OnConflict::KeepFirst => {
+                            path.pop();
+                            continue;
                         }
                     }
+                    move_sources(ans_sources, new_sources, path);
+                    *old = v;
+                }
+            }
+            path.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Record `file` as the source of every leaf (and empty table) in `table`
+fn collect_sources(
+    table: &toml::Table,
+    file: &std::path::Path,
+    sources: &mut Sources,
+    path: &mut Vec<String>,
+) {
+    for (k, v) in table {
+        path.push(k.clone());
+        match v {
+            toml::Value::Table(t) if !t.is_empty() => collect_sources(t, file, sources, path),
+            _ => {
+                let source = Source {
+                    file: file.to_path_buf(),
+                    depth: 0,
+                };
+                sources.insert(path.clone(), source);
+            }
+        }
+        path.pop();
+    }
+}
+
+/// Nest `table` under keys `under`
+fn mount(table: toml::Table, sources: Sources, under: &[String]) -> (toml::Table, Sources) {
+    let mut table = table;
+    for k in under.iter().rev() {
+        table = toml::Table::from_iter([(k.clone(), toml::Value::Table(table))]);
+    }
+    let sources = sources
+        .into_iter()
+        .map(|(path, source)| {
+            let path = under.iter().cloned().chain(path).collect();
+            let source = Source {
+                depth: source.depth + under.len(),
+                ..source
+            };
+            (path, source)
+        })
+        .collect();
+    (table, sources)
+}
+
+/// Sources of all leaves under `path`
+pub fn sources_under<'s>(
+    sources: &'s Sources,
+    path: &'s [String],
+) -> impl Iterator<Item = (&'s Vec<String>, &'s Source)> {
+    sources
+        .range(path.to_vec()..)
+        .take_while(move |(k, _)| k.starts_with(path))
+}
+
+/// Replace sources under `path` of `ans` by those of `new`
+fn move_sources(ans: &mut Sources, new: &Sources, path: &[String]) {
+    let old: Vec<_> = sources_under(ans, path).map(|(k, _)| k.clone()).collect();
+    for k in old {
+        ans.remove(&k);
+    }
+    for (k, source) in sources_under(new, path) {
+        ans.insert(k.clone(), source.clone());
+    }
+}
+
+/// File defining `path`, or `<unknown>`
+pub fn source_of(sources: &Sources, path: &[String]) -> String {
+    match sources_under(sources, path).next() {
+        Some((_, source)) => source.file.display().to_string(),
+        None => "<unknown>".to_owned(),
+    }
+}
+
+/// Key path as written in Toml, quoting keys when needed
+pub fn display_key(path: &[String]) -> String {
+    let bare = |k: &String| {
+        !k.is_empty()
+            && k.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    path.iter()
+        .map(|k| {
+            if bare(k) {
+                k.clone()
+            } else {
+                format!("{:?}", k)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Parse Json file into [`json::Value`] whose `Object` is a `Map<String, json::Value>`.  
+/// Comments and trailing commas are allowed, like VSCode's `settings.json`.
+pub fn parse_json(path: &std::path::Path) -> Result<json::Value> {
+    let content = read(path)?;
+    let stripped = strip_jsonc(&content).map_err(|offset| {
+        Error::parse_at(
+            path,
+            &content,
+            offset,
+            "unterminated block comment".to_owned(),
+        )
+    })?;
+    json::from_str(&stripped).map_err(|err| {
+        let message = err.to_string();
+        // `serde_json` appends the location to its message
+        let message = match message.rfind(" at line ") {
+            Some(i) => message[..i].to_owned(),
+            None => message,
+        };
+        Error::parse(path, &content, err.line(), err.column(), message)
+    })
+}
+
+/// Blank out comments and trailing commas of `JSONC`, so that lines and columns stay the same.  
+/// Returns the offset of an unterminated block comment.
+pub fn strip_jsonc(content: &str) -> std::result::Result<String, usize> {
+    let mut bytes = strip_comments(content)?.into_bytes();
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match (in_string, bytes[i]) {
+            (true, b'\\') => i += 1,
+            (true, b'"') => in_string = false,
+            (false, b'"') => in_string = true,
+            (false, b',') => {
+                let next = bytes[i + 1..].iter().find(|b| !b.is_ascii_whitespace());
+                if matches!(next, Some(b'}' | b']')) {
+                    bytes[i] = b' ';
                 }
             }
+            _ => {}
         }
+        i += 1;
     }
-    Ok(tv)
+    // Only ASCII bytes outside strings were replaced by ASCII spaces
+    Ok(String::from_utf8(bytes).unwrap_or_default())
 }
 
-fn insert_toml_value(ans: &mut toml::map::Map<String, toml::Value>, k: String, v: toml::Value) {
-    if !ans.contains_key(&k) {
-        ans.insert(k, v);
-        return;
+/// Blank out comments of `JSONC` only, like [`strip_jsonc`].
+pub fn strip_comments(content: &str) -> std::result::Result<String, usize> {
+    let mut bytes = content.as_bytes().to_vec();
+    let blank = |bytes: &mut [u8], from: usize, to: usize| {
+        for b in &mut bytes[from..to] {
+            if *b != b'\n' {
+                *b = b' ';
+            }
+        }
+    };
+
+    let mut i = 0;
+    let mut in_string = false;
+    while i < bytes.len() {
+        match (in_string, bytes[i], bytes.get(i + 1)) {
+            (true, b'\\', _) => i += 1,
+            (true, b'"', _) => in_string = false,
+            (true, _, _) => {}
+            (false, b'"', _) => in_string = true,
+            (false, b'/', Some(b'/')) => {
+                let end = (i..bytes.len())
+                    .find(|&j| bytes[j] == b'\n')
+                    .unwrap_or(bytes.len());
+                blank(&mut bytes, i, end);
+                i = end;
+            }
+            (false, b'/', Some(b'*')) => {
+                let end = (i + 2..bytes.len())
+                    .find(|&j| bytes[j..].starts_with(b"*/"))
+                    .ok_or(i)?;
+                blank(&mut bytes, i, end + 2);
+                i = end + 1;
+            }
+            _ => {}
+        }
+        i += 1;
     }
+    // Only ASCII bytes outside strings were replaced by ASCII spaces
+    Ok(String::from_utf8(bytes).unwrap_or_default())
+}
 
-    let av = ans.get_mut(&k).unwrap();
-    match (av, v) {
-        (toml::Value::Table(ref mut ans), toml::Value::Table(map)) => {
-            for (k, v) in map {
-                insert_toml_value(ans, k, v);
+/// Write `content` to a temporary file next to `path` and rename it over `path`,
+/// so readers never see a truncated file.
+/// Symlinks are followed and permissions are kept, the temporary file never stays behind.
+/// The previous file is kept as `path.1`, older ones are shifted up to `path.<backups>`.
+pub fn write_atomic(path: &std::path::Path, content: &str, backups: usize) -> Result<()> {
+    let path = &follow_links(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let replace = || {
+        std::fs::write(&temp, content).map_err(|err| Error::write(path, err))?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            std::fs::set_permissions(&temp, metadata.permissions())
+                .map_err(|err| Error::write(path, err))?;
+        }
+
+        let backup = |i: usize| path.with_file_name(format!("{}.{}", name, i));
+        if backups > 0 && path.is_file() {
+            for i in (1..backups).rev() {
+                if backup(i).exists() {
+                    std::fs::rename(backup(i), backup(i + 1))
+                        .map_err(|err| Error::write(&backup(i + 1), err))?;
+                }
             }
+            std::fs::copy(path, backup(1)).map_err(|err| Error::write(&backup(1), err))?;
         }
-        _ => unreachable!(),
+        std::fs::rename(&temp, path).map_err(|err| Error::write(path, err))
+    };
+    replace().inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp);
+    })
+}
+
+/// Where the symlink `path` finally points to, even if it does not exist yet
+fn follow_links(path: &std::path::Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    // give up on loops
+    for _ in 0..32 {
+        let Ok(target) = std::fs::read_link(&path) else {
+            break;
+        };
+        path = path
+            .parent()
+            .unwrap_or(std::path::Path::new(""))
+            .join(target);
     }
+    path
 }
 
-/// Parse Json file into [`json::Value`] whose `Object` is a `Map<String, json::Value>`
-pub fn parse_json(path: &std::path::Path) -> FmtResult<json::Value> {
-    parse(path)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixture::modules;
+
+    #[test]
+    fn test_include_precedence() -> anyhow::Result<()> {
+        let dir = modules(
+            "precedence",
+            &[
+                ("root", "include = [\"$DIR/a\", \"$DIR/b\"]\n[x]\ny = 3"),
+                ("a", "[x]\ny = 1\nz = 1\nw = 1"),
+                ("b", "[x]\nz = 2"),
+            ],
+        )?;
+        let mut loader = Loader::new(OnConflict::Override);
+        let value = loader.load(&dir.join("root.toml"))?;
+        assert_eq!(value, "x = { y = 3, z = 2, w = 1 }".parse()?);
+        let key = |k: &str| vec!["x".to_owned(), k.to_owned()];
+        assert_eq!(loader.sources[&key("y")].file, dir.join("root.toml"));
+        assert_eq!(loader.sources[&key("z")].file, dir.join("b.toml"));
+
+        loader.on_conflict = OnConflict::KeepFirst;
+        let value = loader.load(&dir.join("root.toml"))?;
+        assert_eq!(value, "x = { y = 1, z = 1, w = 1 }".parse()?);
+
+        loader.on_conflict = OnConflict::Error;
+        match loader.load(&dir.join("root.toml")) {
+            Err(Error::Conflict { key, first, second }) => {
+                assert_eq!(key, "x.z");
+                assert!(first.ends_with("a.toml") && second.ends_with("b.toml"));
+            }
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_includes() -> anyhow::Result<()> {
+        let dir = modules(
+            "relative",
+            &[
+                ("root", "include = [\"sub/a\"]"),
+                ("sub/a", "include = [\"b\", \"root:c\"]\na = 1"),
+                ("sub/b", "b = 1"),
+                ("c", "c = 1"),
+            ],
+        )?;
+
+        let mut loader = Loader::default();
+        let value = loader.load(&dir.join("root.toml"))?;
+        assert_eq!(value, "a = 1\nb = 1\nc = 1".parse()?);
+        assert_eq!(loader.files[2], dir.join("sub").join("b.toml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_graph() -> anyhow::Result<()> {
+        let dir = modules(
+            "graph",
+            &[
+                ("diamond", "include = [\"a\", \"b\"]"),
+                ("a", "include = [\"common\"]\na = 1"),
+                ("b", "include = [\"common\"]\nb = 1"),
+                ("common", "common = 1"),
+                ("cycle", "include = [\"c\"]"),
+                ("c", "include = [\"d\"]"),
+                ("d", "include = [\"c\"]"),
+            ],
+        )?;
+        let mut loader = Loader::new(OnConflict::Error);
+        let value = loader.load(&dir.join("diamond.toml"))?;
+        assert_eq!(value, "a = 1\nb = 1\ncommon = 1".parse()?);
+        assert_eq!(loader.files.len(), 4);
+
+        match loader.load(&dir.join("cycle.toml")) {
+            Err(Error::Include { message, .. }) => {
+                let c = dir.join("c.toml").display().to_string();
+                let d = dir.join("d.toml").display().to_string();
+                assert_eq!(message, format!("cycle {} -> {} -> {}", c, d, c));
+            }
+            other => panic!("Expected cycle, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_includes() -> anyhow::Result<()> {
+        let dir = modules(
+            "glob",
+            &[
+                (
+                    "root",
+                    "include = [\"ext/*\", \"conf.d/\", \"more/*.toml\"]",
+                ),
+                ("ext/b", "x = 2"),
+                ("ext/a", "x = 1\ny = 1"),
+                ("conf.d/local", "y = 3"),
+                ("more/z", "z = 1"),
+            ],
+        )?;
+        dir.write("ext/README.md", "not a module")?;
+
+        let mut loader = Loader::new(OnConflict::Override);
+        let value = loader.load(&dir.join("root.toml"))?;
+        assert_eq!(value, "x = 2\ny = 3\nz = 1".parse()?);
+        let files: Vec<_> = loader.files.iter().skip(1).collect();
+        assert_eq!(
+            files,
+            [
+                &dir.join("ext/a.toml"),
+                &dir.join("ext/b.toml"),
+                &dir.join("conf.d/local.toml"),
+                &dir.join("more/z.toml")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_optional_includes() -> anyhow::Result<()> {
+        let root = r#"include = [
+            { path = "missing", optional = true },
+            { path = "never", when = { env = "THOMSON_TEST_UNSET" } },
+            { path = "always", when = { env = "PATH" } },
+        ]"#;
+        let dir = modules("optional", &[("root", root), ("always", "a = 1")])?;
+        let value = Loader::default().load(&dir.join("root.toml"))?;
+        assert_eq!(value, "a = 1".parse()?);
+
+        std::fs::write(
+            dir.join("root.toml"),
+            "include = [{ path = \"always\", typo = 1 }]",
+        )?;
+        match Loader::default().load(&dir.join("root.toml")) {
+            Err(Error::Include { message, .. }) => assert!(message.contains("typo")),
+            other => panic!("Expected invalid include, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_includes() -> anyhow::Result<()> {
+        let dir = modules(
+            "mount",
+            &[
+                ("root", "include = [{ path = \"vim\", under = \"vim\" }]"),
+                ("vim", "include = [{ path = \"rust\", under = \"'[rust]'.editor\" }]\nleader = \"<space>\""),
+                ("rust", "formatOnSave = true"),
+            ],
+        )?;
+        let mut loader = Loader::default();
+        let value = loader.load(&dir.join("root.toml"))?;
+        let expected = r#"
+            [vim]
+            leader = "<space>"
+            '[rust]'.editor.formatOnSave = true
+        "#;
+        assert_eq!(value, expected.parse()?);
+
+        let key: Vec<_> = ["vim", "[rust]", "editor", "formatOnSave"]
+            .map(String::from)
+            .into();
+        assert_eq!(loader.sources[&key].depth, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_includes() -> anyhow::Result<()> {
+        let dir = modules(
+            "reuse",
+            &[
+                (
+                    "root",
+                    "include = [{ path = \"common\", under = \"a\" }, { path = \"common\", under = \"b\" }, \"common\"]",
+                ),
+                ("common", "x = 1"),
+            ],
+        )?;
+        let mut loader = Loader::default();
+        let value = loader.load(&dir.join("root.toml"))?;
+        assert_eq!(
+            value,
+            "x = 1
+a.x = 1
+b.x = 1"
+                .parse()?
+        );
+        assert_eq!(loader.files.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomic() -> anyhow::Result<()> {
+        let dir = modules("write", &[])?;
+        let file = dir.join("output.json");
+        for i in 1..=4 {
+            write_atomic(&file, &i.to_string(), 2)?;
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.join(name));
+        assert_eq!(read("output.json")?, "4");
+        assert_eq!(read("output.json.1")?, "3");
+        assert_eq!(read("output.json.2")?, "2");
+        assert!(!dir.join("output.json.3").exists());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 3);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let link = dir.join("link.json");
+            std::os::unix::fs::symlink("output.json", &link)?;
+            std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o600))?;
+            write_atomic(&link, "5", 0)?;
+            assert!(link.is_symlink());
+            assert_eq!(read("output.json")?, "5");
+            assert_eq!(
+                std::fs::metadata(&file)?.permissions().mode() & 0o777,
+                0o600
+            );
+            assert_eq!(std::fs::read_dir(&dir)?.count(), 4);
+        }
+
+        // backups cannot be rotated
+        std::fs::remove_file(dir.join("output.json.2"))?;
+        std::fs::create_dir_all(dir.join("output.json.2").join("full"))?;
+        assert!(write_atomic(&file, "6", 2).is_err());
+        let temps = std::fs::read_dir(&dir)?
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .is_ok_and(|e| e.path().extension() == Some("tmp".as_ref()))
+            })
+            .count();
+        assert_eq!(temps, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_jsonc() -> anyhow::Result<()> {
+        let dir = modules("jsonc", &[])?;
+        let file = dir.join("settings.json");
+        let content = "{\n    // \"a\": 0,\n    \"a\": \"// /* kept */\", /* inline */\n    \"b\": [1, 2,],\n}\n";
+        std::fs::write(&file, content)?;
+        assert_eq!(
+            parse_json(&file)?,
+            json::json!({ "a": "// /* kept */", "b": [1, 2] })
+        );
+
+        std::fs::write(&file, "{\n    \"a\": 1, /* oops\n}")?;
+        let Err(Error::Parse { location }) = parse_json(&file) else {
+            panic!("Expected parse error");
+        };
+        assert_eq!((location.line, location.column), (2, 13));
+        Ok(())
+    }
 }
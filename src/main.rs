@@ -1,20 +1,26 @@
 mod collect;
 mod component;
+mod decompile;
+mod diff;
+mod error;
+#[cfg(test)]
+mod fixture;
+mod format;
+mod import;
 mod io;
+mod split;
+mod suggest;
 mod transform;
+mod watch;
 
 use component::driver;
 
-fn main() {
-    env_logger::init();
+fn main() -> std::process::ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let driver = driver::Driver::new();
 
     match driver.run() {
-        Ok(s) => {
-            println!("{}", s);
-        }
-        Err(err) => {
-            log::error!("{:?}", err);
-        }
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(err) => error::report(&err).into(),
     }
 }
@@ -0,0 +1,149 @@
+//! Split one big `TOML` value into include modules by key prefix.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{collect::CONFIG_KEY, component::rule::segments, decompile::render, error::Error, io};
+
+/// How top-level keys are partitioned into modules
+#[derive(Debug, Clone)]
+pub enum Policy {
+    /// One module per key prefix, all under directory `dir`
+    Prefix { dir: String },
+    /// Prefix to module, unmapped prefixes stay in the root file
+    Mapping(HashMap<String, String>),
+}
+
+impl Policy {
+    /// Read a mapping from a `TOML` file like `vim = "extension/vim"`
+    pub fn mapping(path: &std::path::Path) -> anyhow::Result<Self> {
+        Ok(Self::Mapping(io::parse_toml(path)?))
+    }
+
+    /// Which module `key` goes to, `None` for the root file
+    fn module(&self, key: &str) -> Option<String> {
+        let prefix = segments(key)[0];
+        match self {
+            Policy::Prefix { dir } => {
+                let name: String = prefix
+                    .chars()
+                    .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+                    .collect();
+                if name.is_empty() {
+                    return None;
+                }
+                Some(format!("{}/{}", dir, name))
+            }
+            Policy::Mapping(mapping) => mapping.get(prefix).cloned(),
+        }
+    }
+}
+
+/// Root table with `include`s and all modules by name
+#[derive(Debug, Default)]
+pub struct Split {
+    pub root: toml::Table,
+    pub modules: BTreeMap<String, toml::Table>,
+}
+
+impl Split {
+    /// Write root as `root_name` and every module as `<name>.toml` under `dir`.
+    /// Refuse to overwrite any existing file.
+    pub fn write(&self, dir: &std::path::Path, root_name: &str) -> anyhow::Result<()> {
+        let mut files = vec![(dir.join(root_name), render_root(&self.root)?)];
+        for (name, module) in &self.modules {
+            let text = render(&toml::Value::Table(module.clone()))?;
+            files.push((dir.join(format!("{}.toml", name)), text));
+        }
+        if let Some((file, _)) = files.iter().find(|(file, _)| file.exists()) {
+            anyhow::bail!("Refusing to overwrite existing file {}", file.display());
+        }
+
+        for (file, text) in files {
+            if let Some(parent) = file.parent() {
+                std::fs::create_dir_all(parent).map_err(|err| Error::write(parent, err))?;
+            }
+            std::fs::write(&file, text).map_err(|err| Error::write(&file, err))?;
+        }
+        Ok(())
+    }
+}
+
+/// Render root with one include per line
+fn render_root(root: &toml::Table) -> anyhow::Result<String> {
+    let mut doc: toml_edit::DocumentMut = render(&toml::Value::Table(root.clone()))?.parse()?;
+    if let Some(includes) = doc.get_mut("include").and_then(|item| item.as_array_mut()) {
+        for module in includes.iter_mut() {
+            module.decor_mut().set_prefix("\n    ");
+        }
+        includes.set_trailing_comma(true);
+        includes.set_trailing("\n");
+    }
+    Ok(doc.to_string())
+}
+
+/// Partition top-level keys into modules, the root includes them in order.
+pub fn split_by_policy(toml_value: toml::Value, policy: &Policy) -> Split {
+    let mut ans = Split::default();
+    let toml::Value::Table(table) = toml_value else {
+        return ans;
+    };
+    for (k, v) in table {
+        // configuration of `thomson` itself stays in the root
+        let module = match k == CONFIG_KEY {
+            true => None,
+            false => policy.module(&k),
+        };
+        match module {
+            Some(module) => {
+                ans.modules.entry(module).or_default().insert(k, v);
+            }
+            None => {
+                ans.root.insert(k, v);
+            }
+        }
+    }
+
+    let includes = ans
+        .modules
+        .keys()
+        .map(|name| toml::Value::String(name.clone()))
+        .collect();
+    ans.root
+        .insert("include".to_owned(), toml::Value::Array(includes));
+    ans
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_by_prefix() -> anyhow::Result<()> {
+        let toml_value: toml::Value = r#"
+            "editor.fontSize" = 14
+            [vim]
+            leader = "<space>"
+            ['[rust]']
+            editor.formatOnSave = true
+        "#
+        .parse()?;
+
+        let policy = Policy::Prefix {
+            dir: "extension".to_owned(),
+        };
+        let split = split_by_policy(toml_value.clone(), &policy);
+        let names: Vec<_> = split.modules.keys().cloned().collect();
+        assert_eq!(
+            names,
+            ["extension/editor", "extension/rust", "extension/vim"]
+        );
+
+        let mut merged = split.root.clone();
+        merged.remove("include");
+        for module in split.modules.into_values() {
+            merged.extend(module);
+        }
+        assert_eq!(toml::Value::Table(merged), toml_value);
+        Ok(())
+    }
+}
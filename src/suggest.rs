@@ -0,0 +1,55 @@
+//! Did-you-mean suggestions for keys matching no rule.
+
+/// Edit distance between `a` and `b`, by chars
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate closest to `key`, if it is close enough to be a typo of it.
+/// Changing every char of a short key like `y` makes no typo of it.
+/// Ties go to the lexicographically smallest candidate.
+pub fn did_you_mean<'c, Iter>(key: &str, candidates: Iter) -> Option<&'c str>
+where
+    Iter: IntoIterator<Item = &'c str>,
+{
+    let len = key.chars().count();
+    let limit = (len / 4).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (levenshtein(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= limit && *distance < len)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        let known = ["editor.fontSize", "editor.fontFamily", "vim.leader"];
+        assert_eq!(
+            did_you_mean("editor.fontSzie", known),
+            Some("editor.fontSize")
+        );
+        assert_eq!(did_you_mean("vim.leadr", known), Some("vim.leader"));
+        assert_eq!(did_you_mean("workbench.colorTheme", known), None);
+        assert_eq!(did_you_mean("y", ["x"]), None);
+        assert_eq!(did_you_mean("ab", ["ac", "xy"]), Some("ac"));
+        assert_eq!(did_you_mean("ab", ["xy"]), None);
+    }
+}
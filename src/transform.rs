@@ -1,19 +1,38 @@
-use std::{borrow::Cow, collections::HashMap, mem};
+use std::{borrow::Cow, mem};
+
+use itertools::Itertools;
 
 use rule::Rules;
 use serde_json as json;
+use thiserror::Error;
 
 use crate::component::{
     path::Path,
     rule::{self, Key},
 };
 
+#[derive(Error, Debug)]
+pub enum TransformErr {
+    #[error("Value `{value}` at {path} cannot be represented in JSON")]
+    Number { path: String, value: f64 },
+
+    #[error("Value `{new}` at {path} conflicts with `{old}`")]
+    Conflict {
+        path: String,
+        old: Box<json::Value>,
+        new: Box<json::Value>,
+    },
+}
+
+pub type TransformResult<T> = Result<T, TransformErr>;
+
 /// The main logic to transform `TOML` value into `JSON` value by rules
-pub fn transform_by_rules(toml_value: toml::Value, rules: &Rules) -> json::Value {
-    let kv: HashMap<_, _> = map_by_rules(toml_value, rules)
-        .into_iter()
-        .map(|(k, v)| (k, transform(v)))
-        .collect();
+pub fn transform_by_rules(toml_value: toml::Value, rules: &Rules) -> TransformResult<json::Value> {
+    let mut kv = Vec::new();
+    for (k, v) in map_by_rules(toml_value, rules) {
+        let v = transform(v, &k)?;
+        kv.push((k, v));
+    }
 
     // TODO: add context to debug
     // for (k, _) in &kv {
@@ -24,144 +43,355 @@ pub fn transform_by_rules(toml_value: toml::Value, rules: &Rules) -> json::Value
 }
 
 /// Directly transform `TOML` value into `JSON` value without rules.
-fn transform(value: toml::Value) -> json::Value {
-    match value {
+fn transform(value: toml::Value, path: &Path<'_>) -> TransformResult<json::Value> {
+    let value = match value {
         toml::Value::String(s) => json::Value::String(s),
-        toml::Value::Integer(i) => json::Value::Number(json::Number::from_i128(i as i128).unwrap()),
-        toml::Value::Float(n) => json::Value::Number(json::Number::from_f64(n).unwrap()),
+        toml::Value::Integer(i) => json::Value::Number(i.into()),
+        toml::Value::Float(n) => match json::Number::from_f64(n) {
+            Some(n) => json::Value::Number(n),
+            None => {
+                return Err(TransformErr::Number {
+                    path: path.to_string(),
+                    value: n,
+                })
+            }
+        },
         toml::Value::Boolean(b) => json::Value::Bool(b),
         toml::Value::Datetime(datetime) => json::Value::String(datetime.to_string()),
         toml::Value::Array(vec) => {
-            let vs = vec.into_iter().map(|v| transform(v)).collect();
+            let vs = vec
+                .into_iter()
+                .map(|v| transform(v, path))
+                .collect::<TransformResult<_>>()?;
             json::Value::Array(vs)
         }
         toml::Value::Table(map) => {
-            let mut kv = HashMap::new();
-            for (k, v) in map {
-                let v = transform(v);
-                kv.insert(k, v);
-            }
-            json::Value::Object(kv.into_iter().collect())
+            let kv = map
+                .into_iter()
+                .map(|(k, v)| Ok((k, transform(v, path)?)))
+                .collect::<TransformResult<_>>()?;
+            json::Value::Object(kv)
         }
-    }
+    };
+    Ok(value)
 }
 
 /// Transform `TOML` paths by `JSON` rules.  
-/// Keep leaf values.
-pub fn map_by_rules<'v>(toml_value: toml::Value, rules: &Rules) -> HashMap<Path<'v>, toml::Value> {
-    let mut path = Path::empty();
-    let mut collector = HashMap::new();
-    match_rule_dfs(toml_value, rules.root(), &mut path, &mut collector);
+/// Keep leaf values, in the order they appear in `TOML`.
+pub fn map_by_rules<'v>(toml_value: toml::Value, rules: &Rules) -> Vec<(Path<'v>, toml::Value)> {
+    collect(toml_value, rules).leaves
+}
+
+/// Rewrite `JSON` settings the way `TOML` compiles by rules, `null`s included.
+pub fn transform_json_by_rules(
+    json_value: json::Value,
+    rules: &Rules,
+) -> TransformResult<json::Value> {
+    toml_to_json_value(collect(json_value, rules).leaves)
+}
+
+/// Top-level `JSON` key of every leaf, along with the `TOML` key path defining it.
+pub fn owners_by_rules(toml_value: toml::Value, rules: &Rules) -> Vec<(String, Vec<String>)> {
+    collect(toml_value, rules).owners
+}
+
+/// `TOML` key paths matching no rule, which are flattened as they are.
+pub fn unmatched_keys(toml_value: toml::Value, rules: &Rules) -> Vec<Vec<String>> {
+    collect(toml_value, rules).unmatched
+}
+
+/// Values matched against rules, either `TOML` or `JSON`
+trait Tree: Sized {
+    fn shape(self) -> Shape<Self>;
+}
+
+enum Shape<V> {
+    Table(Vec<(String, V)>),
+    Array(Vec<V>),
+    Leaf(V),
+}
+
+impl Tree for toml::Value {
+    fn shape(self) -> Shape<Self> {
+        match self {
+            toml::Value::Table(map) => Shape::Table(map.into_iter().collect()),
+            toml::Value::Array(vs) => Shape::Array(vs),
+            v => Shape::Leaf(v),
+        }
+    }
+}
+
+impl Tree for json::Value {
+    fn shape(self) -> Shape<Self> {
+        match self {
+            json::Value::Object(map) => Shape::Table(map.into_iter().collect()),
+            json::Value::Array(vs) => Shape::Array(vs),
+            v => Shape::Leaf(v),
+        }
+    }
+}
+
+fn collect<'v, V: Tree>(value: V, rules: &Rules) -> Collector<'v, V> {
+    let mut collector = Collector {
+        leaves: Vec::new(),
+        keys: Vec::new(),
+        spans: Vec::new(),
+        unmatched: Vec::new(),
+        owners: Vec::new(),
+    };
+    match_rule_dfs(
+        value,
+        rules.root(),
+        None,
+        &mut Path::empty(),
+        &mut collector,
+    );
     collector
 }
 
-fn match_rule_dfs<'v>(
-    toml_value: toml::Value,
-    node: &rule::Node,
+struct Collector<'v, V> {
+    leaves: Vec<(Path<'v>, V)>,
+    /// `TOML` key path being visited
+    keys: Vec<String>,
+    /// Number of keys of `path` each key of `keys` makes, quoted dotted keys make several
+    spans: Vec<usize>,
+    unmatched: Vec<Vec<String>>,
+    owners: Vec<(String, Vec<String>)>,
+}
+
+impl<'v, V> Collector<'v, V> {
+    fn push(&mut self, path: &Path<'v>, value: V) {
+        let head = path.clone().into_iter().next().unwrap_or_default();
+        let mut spanned = 0;
+        let count = self
+            .spans
+            .iter()
+            .take_while(|span| {
+                let more = spanned < head.len();
+                spanned += *span;
+                more
+            })
+            .count();
+        let json_key = head.iter().map(ToString::to_string).join(".");
+        self.owners.push((json_key, self.keys[..count].to_vec()));
+        self.leaves.push((path.clone(), value));
+    }
+}
+
+fn match_rule_dfs<'n, 'v, V: Tree>(
+    value: V,
+    node: &'n rule::Node,
+    shadow: Option<&'n rule::Node>,
     path: &mut Path<'v>,
-    collector: &mut HashMap<Path<'v>, toml::Value>,
+    collector: &mut Collector<'v, V>,
 ) {
-    match toml_value {
-        toml::Value::Table(map) => {
+    // the rules end here, the whole value belongs to the key
+    if node.is_leaf() && !path.is_empty() {
+        collector.push(path, value);
+        return;
+    }
+    match value.shape() {
+        Shape::Table(map) => {
             for (k, v) in map {
-                // try to match
-                let key = Key::field(k);
+                collector.keys.push(k.clone());
+                collector.spans.push(1);
+                let top = path.is_empty();
+                // try to match, quoted keys like `"guides.bracketPairs"` segment by segment
+                let segments = rule::segments(&k);
+                let key = Key::field(&k);
+                // keys a language override has no rules for are matched as top-level ones
+                let (node, shadow) = match shadow {
+                    Some(shadow)
+                        if !knows(node, &key, &segments) && knows(shadow, &key, &segments) =>
+                    {
+                        (shadow, None)
+                    }
+                    _ => (node, shadow),
+                };
+                let nodes = walk(node, &segments);
                 if let Some(next) = node.get(&key) {
+                    let shadow = match top && is_override(&k) {
+                        true => Some(node),
+                        false => shadow.and_then(|shadow| shadow.get(&key)),
+                    };
                     path.link(next.edge, Cow::Owned(key));
-                    match_rule_dfs(v, next, path, collector);
+                    match_rule_dfs(v, next, shadow, path, collector);
+                } else if top && is_override(&k) {
+                    // language overrides like `[rust]` hold settings of the top level
+                    path.push(Cow::Owned(key));
+                    match_rule_dfs(v, node, None, path, collector);
+                } else if let Some(last) = nodes.last().filter(|_| segments.len() > 1) {
+                    *collector.spans.last_mut().unwrap() = segments.len();
+                    for (segment, next) in segments.iter().zip(&nodes) {
+                        path.link(next.edge, Cow::Owned(Key::field(segment)));
+                    }
+                    let shadow = shadow.and_then(|shadow| walk(shadow, &segments).pop());
+                    match_rule_dfs(v, last, shadow, path, collector);
+                    for _ in 1..segments.len() {
+                        path.pop();
+                    }
                 } else {
+                    collector.unmatched.push(collector.keys.clone());
                     path.flattern(); // cancel all previous adherences
                     path.push(Cow::Owned(key));
-                    collector.insert(path.clone(), v);
+                    collector.push(path, v);
                 }
                 path.pop();
+                collector.keys.pop();
+                collector.spans.pop();
             }
         }
-        toml::Value::Array(vs) => {
+        Shape::Array(vs) => {
             let len = vs.len();
+            let pseudo = node.get(&Key::pseudo_index());
+            if pseudo.is_none() && len > 0 {
+                collector.unmatched.push(collector.keys.clone());
+            }
             for (i, v) in vs.into_iter().enumerate() {
                 let key = Key::index(i, len).unwrap();
-                if let Some(next) = node.get(&Key::pseudo_index()) {
+                if let Some(next) = pseudo {
                     path.link(next.edge, Cow::Owned(key));
-                    match_rule_dfs(v, next, path, collector);
+                    match_rule_dfs(v, next, None, path, collector);
                 } else {
                     path.flattern(); // cancel all previous adherences
                     path.push(Cow::Owned(key));
-                    collector.insert(path.clone(), v);
+                    collector.push(path, v);
                 }
                 path.pop();
             }
         }
-        v => {
-            collector.insert(path.clone(), v);
+        Shape::Leaf(v) => {
+            if !path.is_empty() {
+                // the rules go on, the key is not complete
+                collector.unmatched.push(collector.keys.clone());
+            }
+            collector.push(path, v);
         }
     }
 }
 
-fn insert_json_array(vec: &mut Vec<json::Value>, k: Key, v: json::Value) {
+/// Language override keys like `[rust]` or `[typescript][javascript]`
+pub fn is_override(key: &str) -> bool {
+    key.len() > 2 && key.starts_with('[') && key.ends_with(']')
+}
+
+/// Whether `node` has rules for `key`, or for all `segments` of it
+fn knows(node: &rule::Node, key: &Key, segments: &[&str]) -> bool {
+    node.get(key).is_some() || (segments.len() > 1 && !walk(node, segments).is_empty())
+}
+
+/// Nodes along `segments`, empty if the rules do not have them all
+fn walk<'n>(node: &'n rule::Node, segments: &[&str]) -> Vec<&'n rule::Node> {
+    let mut ans = Vec::new();
+    let mut cur = node;
+    for segment in segments {
+        match cur.get(&Key::field(segment)) {
+            Some(next) => {
+                ans.push(next);
+                cur = next;
+            }
+            None => return Vec::new(),
+        }
+    }
+    ans
+}
+
+fn conflict(path: &Path<'_>, old: json::Value, new: json::Value) -> TransformErr {
+    TransformErr::Conflict {
+        path: path.to_string(),
+        old: Box::new(old),
+        new: Box::new(new),
+    }
+}
+
+fn insert_json_array(
+    vec: &mut Vec<json::Value>,
+    k: Key,
+    v: json::Value,
+    path: &Path<'_>,
+) -> TransformResult<()> {
     match k {
-        Key::Field(_) => panic!(""),
+        Key::Field(k) => {
+            let new = json::Value::Object(json::Map::from_iter([(k, v)]));
+            Err(conflict(path, json::Value::Array(mem::take(vec)), new))
+        }
         Key::Index { of, total } => {
-            if vec.len() < total {
-                panic!("");
+            if vec.len() < total || of >= vec.len() {
+                return Err(conflict(path, json::Value::Array(mem::take(vec)), v));
             }
 
             match (&mut vec[of], v) {
                 (json::Value::Null, v) => vec[of] = v,
                 (slot, json::Value::Object(map)) => {
                     for (k, v) in map {
-                        insert_json_value(slot, Key::field(k), v);
+                        insert_json_value(slot, Key::field(k), v, path)?;
                     }
                 }
                 (_, json::Value::Null) => {
                     // we have inserted a null value
-                    return;
-                }
-                _ => {
-                    unreachable!()
                 }
+                (slot, v) => return Err(conflict(path, slot.take(), v)),
             }
+            Ok(())
         }
     }
 }
 
-fn insert_json_map(map: &mut json::Map<String, json::Value>, k: Key, v: json::Value) {
+fn insert_json_map(
+    map: &mut json::Map<String, json::Value>,
+    k: Key,
+    v: json::Value,
+    path: &Path<'_>,
+) -> TransformResult<()> {
     match k {
         Key::Field(k) => {
-            if !map.contains_key(&k) {
+            let Some(av) = map.get_mut(&k) else {
                 map.insert(k, v);
-                return;
-            }
+                return Ok(());
+            };
 
-            let av = map.get_mut(&k).unwrap();
             match (av, v) {
+                (av @ json::Value::Null, v) => *av = v,
                 (av, serde_json::Value::Object(map)) => {
                     for (k, v) in map {
-                        insert_json_value(av, Key::field(k), v);
+                        insert_json_value(av, Key::field(k), v, path)?;
                     }
                 }
                 (_, json::Value::Null) => {
                     // we have inserted a null value
-                    return;
-                }
-                _ => {
-                    unreachable!()
                 }
+                (av, v) => return Err(conflict(path, av.take(), v)),
             }
+            Ok(())
+        }
+        Key::Index { of, total } => {
+            let mut new = vec![json::Value::Null; total];
+            if let Some(slot) = new.get_mut(of) {
+                *slot = v;
+            }
+            Err(conflict(
+                path,
+                json::Value::Object(mem::take(map)),
+                json::Value::Array(new),
+            ))
         }
-        Key::Index { .. } => unreachable!(),
     }
 }
 
-fn replace_json_value(slot: &mut json::Value, k: Key, v: json::Value) {
+fn replace_json_value(
+    slot: &mut json::Value,
+    k: Key,
+    v: json::Value,
+    path: &Path<'_>,
+) -> TransformResult<()> {
     match k {
         Key::Field(k) => {
-            let v = json::Value::Object(json::Map::from_iter([(k.into(), v)]));
+            let v = json::Value::Object(json::Map::from_iter([(k, v)]));
             let _ = mem::replace(slot, v);
         }
         Key::Index { of, total } => {
             if of >= total {
-                panic!("of >= total");
+                return Err(conflict(path, slot.take(), v));
             }
             let mut vs = vec![json::Value::Null; total];
             vs[of] = v;
@@ -169,40 +399,65 @@ fn replace_json_value(slot: &mut json::Value, k: Key, v: json::Value) {
             let _ = mem::replace(slot, v);
         }
     }
+    Ok(())
 }
 
 /// Never touch existed values.  
 ///
-/// # PANIC
-/// If conflicts.
-fn insert_json_value(ans: &mut json::Value, k: Key, v: json::Value) {
+/// # Errors
+/// If conflicts, with the value of `path`.
+fn insert_json_value(
+    ans: &mut json::Value,
+    k: Key,
+    v: json::Value,
+    path: &Path<'_>,
+) -> TransformResult<()> {
     match ans {
-        serde_json::Value::Array(vec) => insert_json_array(vec, k, v),
-        serde_json::Value::Object(map) => insert_json_map(map, k, v),
-        serde_json::Value::Null => replace_json_value(ans, k, v),
-        _ => unreachable!(),
+        serde_json::Value::Array(vec) => insert_json_array(vec, k, v, path),
+        serde_json::Value::Object(map) => insert_json_map(map, k, v, path),
+        serde_json::Value::Null => replace_json_value(ans, k, v, path),
+        old => {
+            let new = json::Value::Object(json::Map::from_iter([(k.to_string(), v)]));
+            Err(conflict(path, old.take(), new))
+        }
     }
 }
 
-fn toml_to_json_value(kv: HashMap<Path<'_>, json::Value>) -> json::Value {
+fn toml_to_json_value(kv: Vec<(Path<'_>, json::Value)>) -> TransformResult<json::Value> {
     let mut ans = json::Value::Null;
     for (path, v) in kv {
         let mut cur = &mut ans;
-        for key in path.keys() {
-            insert_json_value(cur, key.clone(), json::Value::Null);
+        for key in path.clone().keys() {
+            insert_json_value(cur, key.clone(), json::Value::Null, &path)?;
+            let next = match &key {
+                Key::Field(s) => cur.get(s).is_some(),
+                Key::Index { of, .. } => cur.get(of).is_some(),
+            };
+            if !next {
+                return Err(conflict(&path, cur.take(), v));
+            }
             cur = match key {
                 Key::Field(s) => cur.get_mut(s).unwrap(),
                 Key::Index { of, .. } => cur.get_mut(of).unwrap(),
             }
         }
-        let _ = mem::replace(cur, v);
+        match (cur, v) {
+            (cur @ json::Value::Null, v) => *cur = v,
+            (cur @ json::Value::Object(_), json::Value::Object(map)) => {
+                for (k, v) in map {
+                    insert_json_value(cur, Key::field(k), v, &path)?;
+                }
+            }
+            (cur, v) => return Err(conflict(&path, cur.take(), v)),
+        }
     }
-    ans
+    Ok(ans)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{collect, io};
+    use super::*;
+    use crate::{collect, fixture::modules, io};
     use collect::collect_rules;
 
     #[test]
@@ -215,4 +470,152 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_transform_errors() -> anyhow::Result<()> {
+        let rules = collect_rules(serde_json::json!({ "editor.fontSize": 14 }));
+        let nan: toml::Value = "[editor]\nfontSize = nan".parse()?;
+        assert!(matches!(
+            transform_by_rules(nan, &rules),
+            Err(TransformErr::Number { .. })
+        ));
+
+        let clash: toml::Value = "\"editor.fontSize\" = 14\n[editor]\nfontSize = 15".parse()?;
+        match transform_by_rules(clash, &rules) {
+            Err(TransformErr::Conflict { path, old, new }) => {
+                assert_eq!(path, "[editor.fontSize]");
+                let mut values = [*old, *new];
+                values.sort_by_key(|v| v.to_string());
+                assert_eq!(values, [14, 15].map(serde_json::Value::from));
+            }
+            other => panic!("Expected conflict, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dotted_keys() -> anyhow::Result<()> {
+        let rules = collect_rules(serde_json::json!({
+            "editor.guides.bracketPairs": "active",
+            "files.watcherExclude": { "**/.bloop": true },
+        }));
+        let toml_value: toml::Value = r#"
+            [editor]
+            "guides.bracketPairs" = "active"
+            [files.watcherExclude]
+            '**/.bloop' = true
+        "#
+        .parse()?;
+        let expected = serde_json::json!({
+            "editor.guides.bracketPairs": "active",
+            "files.watcherExclude": { "**/.bloop": true },
+        });
+        assert_eq!(transform_by_rules(toml_value, &rules)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_rules() -> anyhow::Result<()> {
+        let mut toml_value: toml::Value = r#"
+            [thomson.rules]
+            atomic = ["editor.fontSize", "vim.normalModeKeyBindings[]", "vim.handleKeys"]
+
+            [editor]
+            fontSize = 14
+            [vim]
+            handleKeys."<C-a>" = false
+            [[vim.normalModeKeyBindings]]
+            before = ["<leader>", "w"]
+            commands = [":w"]
+            ['[rust]']
+            editor.fontSize = 16
+        "#
+        .parse()?;
+        let inline = collect::take_inline_rules(&mut toml_value)?;
+        let rules = collect::atomic_rules(&inline)?;
+        let expected = serde_json::json!({
+            "editor.fontSize": 14,
+            "vim.handleKeys": { "<C-a>": false },
+            "vim.normalModeKeyBindings": [{ "before": ["<leader>", "w"], "commands": [":w"] }],
+            "[rust]": { "editor.fontSize": 16 },
+        });
+        assert_eq!(transform_by_rules(toml_value, &rules)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_override_fallback() -> anyhow::Result<()> {
+        let rules = collect_rules(serde_json::json!({
+            "editor.tabSize": 4,
+            "[rust]": { "editor.formatOnSave": true },
+        }));
+        let toml_value: toml::Value = r#"
+            ['[rust]']
+            editor.formatOnSave = true
+            editor.tabSize = 2
+        "#
+        .parse()?;
+        let expected = serde_json::json!({
+            "[rust]": { "editor.formatOnSave": true, "editor.tabSize": 2 },
+        });
+        assert_eq!(transform_by_rules(toml_value.clone(), &rules)?, expected);
+        assert!(unmatched_keys(toml_value, &rules).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_order() -> anyhow::Result<()> {
+        let dir = modules(
+            "order",
+            &[
+                (
+                    "settings",
+                    "include = [\"zeta\", \"alpha\"]\n[window]\nzoomLevel = 1\n[editor]\nfontSize = 14\n",
+                ),
+                ("zeta", "[zeta]\nb = 1\na = 2\n"),
+                ("alpha", "[alpha]\nx = 1\n"),
+            ],
+        )?;
+
+        let rules = collect::atomic_rules(&toml::toml! {
+            atomic = ["alpha.x", "editor.fontSize", "window.zoomLevel", "zeta.a", "zeta.b"]
+        })?;
+        let toml_value = io::Loader::default().load(&dir.join("settings.toml"))?;
+        let json_value = transform_by_rules(toml_value, &rules)?;
+        let keys: Vec<_> = json_value.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            [
+                "zeta.b",
+                "zeta.a",
+                "alpha.x",
+                "window.zoomLevel",
+                "editor.fontSize"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unmatched_keys() -> anyhow::Result<()> {
+        let rules = collect::atomic_rules(&toml::toml! {
+            atomic = ["editor.fontSize", "editor.rulers", "vim.handleKeys"]
+        })?;
+        let toml_value: toml::Value = r#"
+            editor.fontSzie = 14
+            editor.rulers = [80, 100]
+            vim = { handleKeys = { "<C-a>" = false }, leader = " " }
+            "[rust]" = { editor = { fontSize = 16, tabSize = 4 } }
+        "#
+        .parse()?;
+        let unmatched: Vec<_> = unmatched_keys(toml_value, &rules)
+            .iter()
+            .map(|keys| keys.join("."))
+            .collect();
+        assert_eq!(
+            unmatched,
+            ["editor.fontSzie", "vim.leader", "[rust].editor.tabSize"]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,117 @@
+//! Watch files by polling their metadata.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// What we remember about a file: `None` if it does not exist (yet).
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &std::path::Path) -> Stamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Polling file watcher with debouncing
+#[derive(Debug)]
+pub struct Watcher {
+    stamps: HashMap<PathBuf, Stamp>,
+    interval: Duration,
+    debounce: Duration,
+}
+
+impl Watcher {
+    /// `interval`: how often files are polled.
+    /// `debounce`: how long files must stay untouched before a change is reported.
+    pub fn new(interval: Duration, debounce: Duration) -> Self {
+        Self {
+            stamps: HashMap::new(),
+            interval,
+            debounce,
+        }
+    }
+
+    /// Start watching `files` in addition to the watched ones
+    pub fn watch<Iter>(&mut self, files: Iter)
+    where
+        Iter: IntoIterator<Item = PathBuf>,
+    {
+        for file in files {
+            let s = stamp(&file);
+            self.stamps.entry(file).or_insert(s);
+        }
+    }
+
+    /// Watch exactly `files`, forgetting all the others
+    pub fn reset<Iter>(&mut self, files: Iter)
+    where
+        Iter: IntoIterator<Item = PathBuf>,
+    {
+        let files: Vec<_> = files.into_iter().collect();
+        self.stamps.retain(|file, _| files.contains(file));
+        self.watch(files);
+    }
+
+    /// Files changed since the last poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        for (file, old) in self.stamps.iter_mut() {
+            let new = stamp(file);
+            if new != *old {
+                *old = new;
+                changed.push(file.clone());
+            }
+        }
+        changed
+    }
+
+    /// Block until some files changed and then settled down
+    pub fn wait(&mut self) -> Vec<PathBuf> {
+        loop {
+            thread::sleep(self.interval);
+            let mut changed = self.poll();
+            if changed.is_empty() {
+                continue;
+            }
+            loop {
+                thread::sleep(self.debounce);
+                let more = self.poll();
+                if more.is_empty() {
+                    break;
+                }
+                changed.extend(more);
+            }
+            changed.sort();
+            changed.dedup();
+            return changed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixture::modules;
+
+    #[test]
+    fn test_poll_changes() -> anyhow::Result<()> {
+        let dir = modules("watch", &[("settings", "a = 1")])?;
+        let file = dir.join("settings.toml");
+
+        let mut watcher = Watcher::new(Duration::ZERO, Duration::ZERO);
+        watcher.watch([file.clone()]);
+        assert!(watcher.poll().is_empty());
+
+        fs::write(&file, "a = 12")?;
+        assert_eq!(watcher.poll(), vec![file.clone()]);
+        assert!(watcher.poll().is_empty());
+
+        fs::remove_file(&file)?;
+        assert_eq!(watcher.poll(), vec![file]);
+        Ok(())
+    }
+}